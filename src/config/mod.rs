@@ -1,4 +1,6 @@
 
+use std::collections::HashMap;
+
 use config_file::FromConfigFile;
 use serde::Deserialize;
 
@@ -8,6 +10,10 @@ pub struct Config {
 	pub bot: Bot,
     pub redis: Redis,
 	pub ai: Ai,
+	#[serde(default)]
+	pub script: Script,
+	#[serde(default)]
+	pub middleware: Middleware,
 }
 
 #[derive(Deserialize, Clone)]
@@ -34,6 +40,445 @@ pub struct Ai {
 	pub init_prompt: String,
 	pub engage_time: i64,
 	pub auto_join: bool,
+	/// Backend used by groups that haven't picked one with `~ai !backend`.
+	#[serde(default = "default_backend")]
+	pub backend: String,
+	#[serde(default)]
+	pub openai: Option<OpenAi>,
+	/// Token budget of the prompt built from local history, system prompt included.
+	#[serde(default = "default_context_tokens")]
+	pub context_tokens: usize,
+	/// Per-model overrides of `context_tokens`.
+	#[serde(default)]
+	pub context_budget: HashMap<String, usize>,
+	/// Turns kept in each conversation's Redis history list.
+	#[serde(default = "default_history_len")]
+	pub history_len: isize,
+	/// Send replies piece by piece while the backend is still generating.
+	/// Groups can override this with `~ai !stream on|off`.
+	#[serde(default)]
+	pub stream: bool,
+	/// Characters a piece needs before it's sent, except the first sentence.
+	#[serde(default = "default_stream_min_chars")]
+	pub stream_min_chars: usize,
+	/// Minimum milliseconds between two pieces.
+	#[serde(default = "default_stream_interval_ms")]
+	pub stream_interval_ms: u64,
+	/// Backend requests allowed in flight at once, across all conversations.
+	#[serde(default = "default_max_concurrent")]
+	pub max_concurrent: usize,
+	/// Recent group messages kept per group and shown to the AI when it is @-mentioned. 0 disables.
+	#[serde(default = "default_recent_messages")]
+	pub recent_messages: usize,
+	/// Summarise and restart a conversation once its history has this many turns. 0 disables.
+	#[serde(default)]
+	pub summarize_turns: usize,
+	/// Same, by estimated tokens of the history. 0 disables.
+	#[serde(default)]
+	pub summarize_tokens: usize,
+	/// Let the model call tools. Groups can override this with `~ai !tools on|off`.
+	/// Replies that may use tools are never streamed.
+	#[serde(default)]
+	pub tools: bool,
+	/// Tool-call rounds allowed per reply before the model must answer.
+	#[serde(default = "default_max_tool_rounds")]
+	pub max_tool_rounds: usize,
+	/// Bot commands the `run_command` tool may run, without the leading `~`.
+	#[serde(default = "default_tool_commands")]
+	pub tool_commands: Vec<String>,
+	/// Models `~ai !model` may select. Empty means the backends' default models only.
+	#[serde(default)]
+	pub models: Vec<Model>,
+	/// Estimated tokens a group may use per day. 0 means unlimited.
+	#[serde(default)]
+	pub quota_group_daily: u64,
+	/// Same, per user across all chats. The owner is exempt.
+	#[serde(default)]
+	pub quota_user_daily: u64,
+	#[serde(default)]
+	pub moderation: Moderation,
+	#[serde(default)]
+	pub join: Join,
+	#[serde(default)]
+	pub retry: Retry,
+	#[serde(default)]
+	pub kb: Kb,
+	#[serde(default)]
+	pub draw: Draw,
+	#[serde(default)]
+	pub voice: Voice,
+	#[serde(default)]
+	pub tts: Tts,
+	#[serde(default)]
+	pub vision: Vision,
+	/// Templates by name, e.g. `message` or `error.timeout`, replacing the built-in
+	/// framing and error messages. Groups can override them with `~ai !template`.
+	#[serde(default)]
+	pub templates: HashMap<String, String>,
+	/// Language of the error messages users see: `zh` or `en`.
+	#[serde(default = "default_locale")]
+	pub locale: String,
+}
+
+fn default_locale() -> String {
+	"zh".to_string()
+}
+
+/// Retries and circuit breaking of backend requests, `[ai.retry]`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Retry {
+	/// Extra attempts after a timeout, rate limit or server error.
+	pub max_retries: u32,
+	/// Wait before the first retry; doubled for each one after.
+	pub base_delay_ms: u64,
+	/// Consecutive failures that open a backend's circuit.
+	pub breaker_threshold: u32,
+	/// Seconds an open circuit fails requests without trying the backend.
+	pub breaker_cooldown: u64,
+}
+
+impl Default for Retry {
+	fn default() -> Self {
+		Retry {
+			max_retries: 2,
+			base_delay_ms: 500,
+			breaker_threshold: 5,
+			breaker_cooldown: 60,
+		}
+	}
+}
+
+/// Image generation for `~draw`, `[ai.draw]`. Off until an endpoint is set here or in `[ai.openai]`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Draw {
+	/// Base URL of an OpenAI-compatible `/images/generations` API, including `/v1`.
+	/// Falls back to the `[ai.openai]` endpoint and token.
+	pub endpoint: Option<String>,
+	pub token: Option<String>,
+	pub model: String,
+	pub size: String,
+	/// Images each user may generate per day. 0 means unlimited; the owner is exempt.
+	/// The refusal can be replaced with the `draw.quota` template.
+	pub quota_user_daily: u64,
+}
+
+impl Default for Draw {
+	fn default() -> Self {
+		Draw {
+			endpoint: None,
+			token: None,
+			model: "dall-e-3".to_string(),
+			size: "1024x1024".to_string(),
+			quota_user_daily: 5,
+		}
+	}
+}
+
+/// Transcription of voice messages, `[ai.voice]`. Off until an endpoint is set here or in `[ai.openai]`.
+/// WAV, MP3, OGG and FLAC recordings are sent as they are. QQ's SILK and AMR ones are converted
+/// entirely by OneBot's `get_record` (with ffmpeg on its side); the bot decodes no audio itself.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Voice {
+	/// Base URL of a Whisper-compatible `/audio/transcriptions` API, including `/v1`.
+	/// Falls back to the `[ai.openai]` endpoint and token.
+	pub endpoint: Option<String>,
+	pub token: Option<String>,
+	pub model: String,
+	/// Spoken language hint, e.g. `zh`. Detected when unset.
+	pub language: Option<String>,
+	/// Also post each transcript to the group, not only to the AI.
+	pub echo: bool,
+	/// Longest recording transcribed, in KiB. Larger ones are not downloaded past this.
+	pub max_kb: u64,
+}
+
+impl Default for Voice {
+	fn default() -> Self {
+		Voice {
+			endpoint: None,
+			token: None,
+			model: "whisper-1".to_string(),
+			language: None,
+			echo: false,
+			max_kb: 2048,
+		}
+	}
+}
+
+/// Speech synthesis for spoken replies and `~say`, `[ai.tts]`. Off until an endpoint is set here or in `[ai.openai]`.
+/// Groups turn spoken replies on with `~ai !speak`, or by using a persona with a `voice`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Tts {
+	/// Base URL of an OpenAI-compatible `/audio/speech` API, including `/v1`.
+	/// Falls back to the `[ai.openai]` endpoint and token.
+	pub endpoint: Option<String>,
+	pub token: Option<String>,
+	pub model: String,
+	/// Voice used unless the persona names one.
+	pub voice: String,
+	/// Longer replies are sent as text.
+	pub max_chars: usize,
+}
+
+impl Default for Tts {
+	fn default() -> Self {
+		Tts {
+			endpoint: None,
+			token: None,
+			model: "tts-1".to_string(),
+			voice: "alloy".to_string(),
+			max_chars: 200,
+		}
+	}
+}
+
+/// How images are described to the AI, `[ai.vision]`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Vision {
+	/// `monica` uploads the image to Monica; `openai` sends it inline as base64 to
+	/// an OpenAI-compatible vision model. Unset follows the group's chat backend.
+	pub backend: Option<String>,
+	/// For `openai`: base URL including `/v1`, falling back to the `[ai.openai]` endpoint and token.
+	pub endpoint: Option<String>,
+	pub token: Option<String>,
+	/// For `openai`: falls back to `[ai.openai]`'s `vision_model`, then the first catalogued
+	/// `openai` model with `vision = true`. Without any, images go to Monica.
+	pub model: Option<String>,
+	/// What the vision model is asked about each image.
+	pub prompt: String,
+	/// Larger images are described by their summary only, by either backend, and are not
+	/// downloaded past this.
+	pub max_kb: u64,
+	/// How long to wait for a description, including Monica's upload processing.
+	pub timeout_secs: u64,
+}
+
+impl Default for Vision {
+	fn default() -> Self {
+		Vision {
+			backend: None,
+			endpoint: None,
+			token: None,
+			model: None,
+			prompt: "用简洁的语言解释这张图片".to_string(),
+			max_kb: 4096,
+			timeout_secs: 30,
+		}
+	}
+}
+
+/// Per-group knowledge base managed with `~kb`, `[ai.kb]`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Kb {
+	/// Passages added to the system prompt of each turn; 0 turns retrieval off.
+	pub top_k: usize,
+	/// Documents are split into passages of about this many characters.
+	pub passage_chars: usize,
+	/// Largest text file `~kb add` accepts, in KiB.
+	pub max_file_kb: u64,
+}
+
+impl Default for Kb {
+	fn default() -> Self {
+		Kb {
+			top_k: 3,
+			passage_chars: 400,
+			max_file_kb: 512,
+		}
+	}
+}
+
+/// When the bot speaks up unasked while `auto_join` is on, `[ai.join]`.
+/// Groups can override `probability`, `cooldown` and `rate` with `~ai !join`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Join {
+	/// Chance of answering a message that passed the other checks.
+	pub probability: f64,
+	/// Seconds of silence after an unasked reply.
+	pub cooldown: u64,
+	/// Unasked replies allowed per `rate_window` seconds. 0 means no limit.
+	pub rate: u64,
+	pub rate_window: u64,
+	/// Messages containing one of these skip the probability roll.
+	pub keywords: Vec<String>,
+	/// Ask the model whether the message deserves an answer before answering it.
+	pub classifier: bool,
+}
+
+impl Default for Join {
+	fn default() -> Self {
+		Join {
+			probability: 0.3,
+			cooldown: 30,
+			rate: 10,
+			rate_window: 600,
+			keywords: vec![],
+			classifier: false,
+		}
+	}
+}
+
+/// Filtering of user prompts and AI replies, `[ai.moderation]`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Moderation {
+	/// Words that may not appear, matched case-insensitively.
+	pub keywords: Vec<String>,
+	/// Regular expressions that may not match.
+	pub patterns: Vec<String>,
+	/// `replace` masks blocklist hits in replies with `replacement`; `refuse` drops the reply.
+	/// Prompts with a hit are always refused.
+	pub action: String,
+	pub replacement: String,
+	/// Said instead of a refused prompt or reply.
+	pub refusal: String,
+	/// Hide mainland phone and ID card numbers.
+	pub redact_pii: bool,
+	/// OpenAI-compatible `/moderations` URL. Flagged text is refused.
+	pub classifier_endpoint: Option<String>,
+	pub classifier_token: String,
+	pub classifier_model: Option<String>,
+}
+
+impl Default for Moderation {
+	fn default() -> Self {
+		Moderation {
+			keywords: vec![],
+			patterns: vec![],
+			action: "replace".to_string(),
+			replacement: "***".to_string(),
+			refusal: "这个话题我不方便回答，换个话题吧".to_string(),
+			redact_pii: true,
+			classifier_endpoint: None,
+			classifier_token: String::new(),
+			classifier_model: None,
+		}
+	}
+}
+
+fn default_max_tool_rounds() -> usize {
+	4
+}
+
+fn default_tool_commands() -> Vec<String> {
+	vec!["ping".to_string(), "echo".to_string()]
+}
+
+fn default_max_concurrent() -> usize {
+	4
+}
+
+fn default_stream_min_chars() -> usize {
+	80
+}
+
+fn default_stream_interval_ms() -> u64 {
+	1500
+}
+
+fn default_context_tokens() -> usize {
+	4096
+}
+
+fn default_recent_messages() -> usize {
+	20
+}
+
+fn default_history_len() -> isize {
+	200
+}
+
+fn default_backend() -> String {
+	"monica".to_string()
+}
+
+/// An entry of the model catalogue, `[[ai.models]]`.
+#[derive(Deserialize, Clone)]
+pub struct Model {
+	pub name: String,
+	/// Backend that serves this model.
+	pub backend: String,
+	/// Key for the model's conversation state, and Monica's bot ID.
+	/// Defaults to the name with `-` and `.` replaced by `_`.
+	#[serde(default)]
+	pub bot_uid: Option<String>,
+	/// Whether the model understands images itself. Without a vision model set in
+	/// `[ai.vision]` or `[ai.openai]`, the first such `openai` model describes images.
+	#[serde(default)]
+	pub vision: bool,
+	/// Context length in tokens, used as the prompt budget instead of `context_tokens`.
+	#[serde(default)]
+	pub context: Option<usize>,
+}
+
+/// Any server speaking the OpenAI `/v1/chat/completions` protocol.
+#[derive(Deserialize, Clone)]
+pub struct OpenAi {
+	/// Base URL including `/v1`, e.g. `http://localhost:8080/v1`.
+	pub endpoint: String,
+	#[serde(default)]
+	pub token: String,
+	pub default_model: String,
+	/// Model used for images. Falls back to a catalogued model with `vision = true`.
+	#[serde(default)]
+	pub vision_model: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Script {
+	/// Directory scanned for `*.rhai` files.
+	pub dir: String,
+	/// Seconds between checks for changed scripts.
+	pub reload_interval: u64,
+	/// Upper bound on Rhai operations per call, to stop runaway loops.
+	pub max_operations: u64,
+}
+
+impl Default for Script {
+	fn default() -> Self {
+		Script {
+			dir: "plugins".to_string(),
+			reload_interval: 5,
+			max_operations: 1_000_000,
+		}
+	}
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Middleware {
+	/// Names of the middlewares to run, in this order.
+	pub enabled: Vec<String>,
+	/// Users whose events are dropped by `blocklist`.
+	pub blocked_users: Vec<u64>,
+	/// Groups whose events are dropped by `blocklist`.
+	pub blocked_groups: Vec<u64>,
+	/// Messages to the bot (@-mentions and private commands) allowed per user per `rate_window` seconds.
+	pub rate_limit: u64,
+	pub rate_window: i64,
+	/// Characters of text allowed in one outgoing message before `length` truncates it.
+	pub max_length: usize,
+}
+
+impl Default for Middleware {
+	fn default() -> Self {
+		Middleware {
+			enabled: vec![],
+			blocked_users: vec![],
+			blocked_groups: vec![],
+			rate_limit: 10,
+			rate_window: 60,
+			max_length: 3000,
+		}
+	}
 }
 
 
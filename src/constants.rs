@@ -1,4 +1,5 @@
 use lazy_static::lazy_static;
+use std::collections::HashMap;
 use std::sync::RwLock;
 
 lazy_static! {
@@ -9,6 +10,22 @@ lazy_static! {
     pub static ref AI_ENGAGE_TIME: RwLock<i64> = RwLock::new(60);
     pub static ref INIT_PROMPT: RwLock<String> = RwLock::new(String::from(""));
     pub static ref AI_AUTO_JOIN: RwLock<bool> = RwLock::new(false);
+    pub static ref AI_DEFAULT_BACKEND: RwLock<String> = RwLock::new(String::from("monica"));
+    pub static ref AI_CONTEXT_TOKENS: RwLock<usize> = RwLock::new(4096);
+    pub static ref AI_CONTEXT_BUDGET: RwLock<HashMap<String, usize>> = RwLock::new(HashMap::new());
+    pub static ref AI_HISTORY_LEN: RwLock<isize> = RwLock::new(200);
+    pub static ref AI_STREAM: RwLock<bool> = RwLock::new(false);
+    pub static ref AI_STREAM_MIN_CHARS: RwLock<usize> = RwLock::new(80);
+    pub static ref AI_STREAM_INTERVAL_MS: RwLock<u64> = RwLock::new(1500);
+    pub static ref AI_MAX_CONCURRENT: RwLock<usize> = RwLock::new(4);
+    pub static ref AI_SUMMARIZE_TURNS: RwLock<usize> = RwLock::new(0);
+    pub static ref AI_SUMMARIZE_TOKENS: RwLock<usize> = RwLock::new(0);
+    pub static ref AI_TOOLS: RwLock<bool> = RwLock::new(false);
+    pub static ref AI_MAX_TOOL_ROUNDS: RwLock<usize> = RwLock::new(4);
+    pub static ref AI_TOOL_COMMANDS: RwLock<Vec<String>> = RwLock::new(vec![]);
+    pub static ref AI_QUOTA_GROUP_DAILY: RwLock<u64> = RwLock::new(0);
+    pub static ref AI_QUOTA_USER_DAILY: RwLock<u64> = RwLock::new(0);
+    pub static ref AI_RECENT_MESSAGES: RwLock<usize> = RwLock::new(20);
 }
 pub fn set_owner_id(id: u64) {
     *OWNER_ID.write().unwrap() = id;
@@ -37,4 +54,50 @@ pub fn set_ai_engage_time(time: i64) {
 }
 pub fn set_ai_auto_join(auto_join: bool) {
     *AI_AUTO_JOIN.write().unwrap() = auto_join;
-}
\ No newline at end of file
+}
+
+pub fn set_ai_default_backend(backend: String) {
+    *AI_DEFAULT_BACKEND.write().unwrap() = backend;
+}
+
+pub fn set_ai_context_tokens(tokens: usize) {
+    *AI_CONTEXT_TOKENS.write().unwrap() = tokens;
+}
+
+pub fn set_ai_context_budget(budget: HashMap<String, usize>) {
+    *AI_CONTEXT_BUDGET.write().unwrap() = budget;
+}
+
+pub fn set_ai_history_len(len: isize) {
+    *AI_HISTORY_LEN.write().unwrap() = len;
+}
+
+pub fn set_ai_stream(stream: bool, min_chars: usize, interval_ms: u64) {
+    *AI_STREAM.write().unwrap() = stream;
+    *AI_STREAM_MIN_CHARS.write().unwrap() = min_chars;
+    *AI_STREAM_INTERVAL_MS.write().unwrap() = interval_ms;
+}
+
+pub fn set_ai_max_concurrent(max: usize) {
+    *AI_MAX_CONCURRENT.write().unwrap() = max;
+}
+
+pub fn set_ai_summarize(turns: usize, tokens: usize) {
+    *AI_SUMMARIZE_TURNS.write().unwrap() = turns;
+    *AI_SUMMARIZE_TOKENS.write().unwrap() = tokens;
+}
+
+pub fn set_ai_tools(tools: bool, max_rounds: usize, commands: Vec<String>) {
+    *AI_TOOLS.write().unwrap() = tools;
+    *AI_MAX_TOOL_ROUNDS.write().unwrap() = max_rounds;
+    *AI_TOOL_COMMANDS.write().unwrap() = commands;
+}
+
+pub fn set_ai_recent_messages(n: usize) {
+    *AI_RECENT_MESSAGES.write().unwrap() = n;
+}
+
+pub fn set_ai_quota(group_daily: u64, user_daily: u64) {
+    *AI_QUOTA_GROUP_DAILY.write().unwrap() = group_daily;
+    *AI_QUOTA_USER_DAILY.write().unwrap() = user_daily;
+}
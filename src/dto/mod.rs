@@ -27,6 +27,20 @@ impl Data {
             data: Value::Object(json!({ "id": msg }).as_object().unwrap().clone()),
         }
     }
+    /// `file` is a URL, a `file://` path or `base64://` data.
+    pub fn image(file: String) -> Data {
+        Data {
+            type_: "image".to_string(),
+            data: Value::Object(json!({ "file": file }).as_object().unwrap().clone()),
+        }
+    }
+    /// A voice message; `file` as for `image`. QQ sends it alone, without other segments.
+    pub fn record(file: String) -> Data {
+        Data {
+            type_: "record".to_string(),
+            data: Value::Object(json!({ "file": file }).as_object().unwrap().clone()),
+        }
+    }
 }
 
 
@@ -34,6 +48,9 @@ impl Data {
 pub struct RetMessage {
     pub action: String,
     pub params: Value,
+    /// Set on API calls whose response we wait for; the server sends it back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo: Option<String>,
 }
 #[derive(Deserialize, Debug, Clone)]
 pub struct ImgData {
@@ -42,6 +59,14 @@ pub struct ImgData {
     pub url: String,
     pub file_size: String,
 }
+/// A voice message. Which fields are filled depends on the OneBot implementation.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RecordData {
+    pub file: String,
+    pub url: String,
+    pub path: String,
+}
 
 
 
@@ -1,30 +1,99 @@
 pub mod group;
 pub mod private;
 
-use std::sync::Arc;
-use crate::dto::RetMessage;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use crate::dto::{Data, RetMessage};
+use crate::middleware::{self, Flow};
+use crate::plugin::{self, MessageContext};
 
 use futures::{stream::SplitSink, SinkExt as _};
-use tokio::{net::TcpStream, sync::Mutex};
+use once_cell::sync::Lazy;
+use tokio::{net::TcpStream, sync::{oneshot, Mutex}};
+use tokio::time::{timeout, Duration};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use tungstenite::Message;
 use serde_json::Value;
 use redis::Client;
+use uuid::Uuid;
 
 pub type Sender = Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>;
 pub type DynErr = Box<dyn std::error::Error + Send + Sync>;
 
-async fn send(response: RetMessage, sender: Sender) -> Result<(), DynErr> {
+pub(crate) async fn send(response: RetMessage, sender: Sender) -> Result<(), DynErr> {
 	let j = serde_json::to_string(&response).unwrap();
 	let message = Message::Text(j.into());
 	sender.lock().await.send(message).await?;
 	Ok(())
 }
 
+/// API calls waiting for their response, by `echo`.
+static PENDING: Lazy<StdMutex<HashMap<String, oneshot::Sender<Value>>>> = Lazy::new(|| StdMutex::new(HashMap::new()));
+
+/// Calls OneBot API `action` and waits for its `data`.
+pub(crate) async fn call_api(action: &str, params: Value, sender: Sender) -> Result<Value, DynErr> {
+	let echo = Uuid::new_v4().to_string();
+	let (tx, rx) = oneshot::channel();
+	PENDING.lock().unwrap().insert(echo.clone(), tx);
+	let request = RetMessage { action: action.to_string(), params, echo: Some(echo.clone()) };
+	if let Err(e) = send(request, sender).await {
+		PENDING.lock().unwrap().remove(&echo);
+		return Err(e);
+	}
+	let resp = match timeout(Duration::from_secs(10), rx).await {
+		Ok(resp) => resp?,
+		Err(_) => {
+			PENDING.lock().unwrap().remove(&echo);
+			return Err(format!("Timeout waiting for {}", action).into());
+		}
+	};
+	if resp["status"] != "ok" {
+		return Err(format!("{} failed: {}", action, resp).into());
+	}
+	Ok(resp["data"].clone())
+}
+
+/// A message to the group `group_id`, or to `user_id` in private.
+pub(crate) fn reply_to(group_id: Option<u64>, user_id: u64, message: Vec<Data>) -> RetMessage {
+	match group_id {
+		Some(gid) => group::resp(message, gid),
+		None => private::resp(message, user_id),
+	}
+}
+
+/// What the chat sees when a command or plugin fails: a friendly, localised reply.
+/// The error itself only goes to the log and, with the `ai` feature, to the owner.
+pub(crate) async fn failure(ctx: &MessageContext, e: DynErr) -> Vec<Data> {
+	#[cfg(feature = "ai")]
+	return crate::module::ai::retry::friendly(ctx, e).await;
+	#[cfg(not(feature = "ai"))]
+	{
+		log::error!("[{}] <=err] {:?}", ctx.msg_id, e);
+		vec![Data::reply(ctx.msg_id), Data::string("Something went wrong. Please try again later.".to_string())]
+	}
+}
+
+/// Runs a `~cmd args...` message through the plugin registry.
+async fn process_command(ctx: &MessageContext) -> Result<Vec<Data>, DynErr> {
+	let mut msg = ctx.text.split_whitespace();
+	let cmd = msg.next().unwrap();
+	let args = msg.collect::<Vec<&str>>();
+
+	plugin::registry().command(ctx, &cmd[1..], &args).await
+}
+
 pub async fn recv(msg: &str, sender: Sender, db: Arc<Client>) -> Result<(), DynErr>
 {
 	let msg = msg.to_string();
-	let msg: Value = serde_json::from_str(&msg).unwrap();
+	let mut msg: Value = serde_json::from_str(&msg).unwrap();
+
+	if let Some(echo) = msg["echo"].as_str() {
+		let waiting = PENDING.lock().unwrap().remove(echo);
+		if let Some(tx) = waiting {
+			let _ = tx.send(msg);
+			return Ok(());
+		}
+	}
 
 	if let Some(status) = msg["status"].as_str(){
 		if status != "ok"{
@@ -34,25 +103,33 @@ pub async fn recv(msg: &str, sender: Sender, db: Arc<Client>) -> Result<(), DynE
 		}
 	}
 
+	if middleware::pre(&mut msg, db.clone()).await? == Flow::Drop {
+		return Ok(());
+	}
+
 	let resp = match msg["post_type"].as_str().unwrap(){
 		"message" => {
 			match msg["message_type"].as_str().unwrap(){
 				"group" => {
-					group::handle(&msg, db).await
+					group::handle(&msg, db.clone(), sender.clone()).await
 				}
 				"private" => {
-					private::handle(&msg, db).await
+					private::handle(&msg, db.clone(), sender.clone()).await
 				}
 				_ => {
 					Ok(None)
 				}
 			}
 		}
+		"notice" => {
+			plugin::registry().notice(&msg, db.clone()).await
+		}
 		_ => {
 			Ok(None)
 		}
 	};
-	if let Some(resp) = resp? {
+	if let Some(mut resp) = resp? {
+		middleware::post(&msg, &mut resp, db).await?;
 		send(resp, sender).await.unwrap_or_else(|e| {
 			log::error!("Error sending message: {:?}", e);
 		});
@@ -1,79 +1,32 @@
 use std::sync::Arc;
 
 use serde::Serialize;
-use serde_json::{Map, Value};
+use serde_json::Value;
 use super::super::dto::{Data, RetMessage};
-use super::DynErr;
-use super::super::constants::OWNER_ID;
+use super::{failure, process_command, DynErr, Sender};
+use crate::plugin::MessageContext;
 use redis::Client;
 
-async fn process_command(msg: &str, sender: &Map<String, Value>, db: Arc<Client>) -> Result<Vec<Data>, DynErr> {
-    let mut msg = msg.split_whitespace();
-    let cmd = msg.next().unwrap();
-    let args = msg.collect::<Vec<&str>>();
-
-    let ret = match &cmd[1..] {
-        "echo" => {
-            vec![Data::string(args.join(" "))]
-        }
-        "ping" => {
-            crate::module::ping::ping(&sender["nickname"].as_str().unwrap())
-        }
-        "exec" => {
-            if sender["user_id"].as_u64().unwrap() != *OWNER_ID.read().unwrap() {
-				return Ok(vec![Data::string("Permission denied: Owner required".to_string())]);
-			}
-            crate::module::exec::exec(&args.join(" "))?
-        }
-        "ai" => {
-            if args.get(0) == Some(&"!clear") {
-                crate::module::ai::clear_record(0, db.clone(), "main").await?;
-                if args.get(1) == Some(&"all") {
-                    let bots = vec!["gemini_2_0".to_string(), "jv6tFQ5q".to_string(), "zzWzZzSg".to_string()];
-                    for bot in bots {
-                        crate::module::ai::clear_record(0, db.clone(), &bot).await?
-                    }
-                }
-                vec![Data::string("Record cleared".to_string())]
-            } else if args.get(0) == Some(&"!model") {
-                crate::module::ai::set_model(0, db, args.get(1).unwrap_or(&"")).await?
-            } else {
-				crate::module::ai::main_conversation(None, db, &args.join(" ")).await?
-            }
-        }
-        _ => {
-            vec![Data::string("Unknown command".to_string())]
-        }
-    };
-
-    Ok(ret)
-}
-
-fn _default_handler(_msg: &str, _sender: &Map<String, Value>) -> Result<Vec<Data>, DynErr> {
-    Ok(vec![])
-}
-
 #[derive(Serialize, Debug, Clone)]
 struct PrivateMessageParams {
 	user_id: String,
 	message: Vec<Data>,
 }
 
-fn resp(r: Result<Vec<Data>, DynErr>, uid: u64) -> RetMessage {
-
-    let re = r.unwrap_or_else(|e| vec![Data::string(format!("Error: {:?}", e))]);
+pub(super) fn resp(message: Vec<Data>, uid: u64) -> RetMessage {
     let v = serde_json::to_value(PrivateMessageParams {
         user_id: uid.to_string(),
-        message: re,
+        message,
     }).unwrap();
 
     RetMessage {
         action: "send_private_msg".to_string(),
         params: v,
+        echo: None,
     }
 }
 
-pub async fn handle(msg: &Value, db: Arc<Client>) -> Result<Option<RetMessage>, DynErr> 
+pub async fn handle(msg: &Value, db: Arc<Client>, sender: Sender) -> Result<Option<RetMessage>, DynErr>
 {
     let s = msg["sender"].as_object().unwrap();
     let m = msg["message"].as_array().unwrap();
@@ -87,11 +40,30 @@ pub async fn handle(msg: &Value, db: Arc<Client>) -> Result<Option<RetMessage>,
         }
     }
 
-    let v = if in_msg.starts_with("~") {
-        process_command(&in_msg, &s, db).await
-    } else {
+    let ctx = MessageContext {
+        msg_id: msg["message_id"].as_u64().unwrap_or_default(),
+        group_id: None,
+        user_id: s["user_id"].as_u64().unwrap(),
+        nickname: s["nickname"].as_str().unwrap_or_default().to_owned(),
+        sender: s.clone(),
+        text: in_msg,
+        images: vec![],
+        records: vec![],
+        at_self: true,
+        db,
+        event: msg.clone(),
+        outbox: Some(sender),
+    };
+
+    if !ctx.text.starts_with("~") {
         return Ok(None);
+    }
+    let v = match process_command(&ctx).await {
+        Ok(v) => v,
+        Err(e) => failure(&ctx, e).await,
     };
+    if v.is_empty() {
+        return Ok(None);
+    }
     Ok(Some(resp(v, msg["target_id"].as_u64().unwrap())))
 }
-
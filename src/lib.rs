@@ -0,0 +1,72 @@
+pub mod config;
+pub mod handler;
+pub mod dto;
+pub mod module;
+pub mod constants;
+pub mod plugin;
+pub mod middleware;
+
+use tokio_tungstenite::connect_async;
+use futures::StreamExt;
+use redis::Client;
+use log::{info,error};
+use log::LevelFilter;
+
+use plugin::PluginRegistry;
+use middleware::Pipeline;
+
+
+/// Connects to the OneBot server and dispatches events until the process is stopped.
+///
+/// The binary calls this with `PluginRegistry::with_builtins()` and `Pipeline::with_builtins()`;
+/// other crates can register their own plugins and middlewares before handing them over.
+pub async fn run(registry: PluginRegistry, pipeline: Pipeline) -> std::io::Result<()> {
+    let config = config::init_config().await;
+
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+
+    // Set owner ID at startup
+    constants::set_owner_id(config.bot.owner);
+
+    // Initialize Redis connection
+    let db = Client::open(config.redis.url.clone()).unwrap();
+    let arc_db = std::sync::Arc::new(db);
+
+    middleware::install(pipeline, &config);
+    let registry = plugin::install(registry);
+    registry.load(&config, arc_db.clone()).await;
+
+    let addr = config.api.url + "/?access_token=" + config.api.access_token.as_str();
+
+    let (socket, response) = connect_async(addr).await.unwrap();
+
+    info!("Connected to the server: {:?}", response);
+
+    let (sender, mut receiver) = socket.split();
+
+    let arc_sender = std::sync::Arc::new(tokio::sync::Mutex::new(sender));
+
+    loop {
+        let msg = tokio::select! {
+            msg = receiver.next() => msg.unwrap().unwrap(),
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        if msg.is_text() {
+            let msg = msg.to_text().unwrap().to_string();
+            let sender_clone = arc_sender.clone();
+            let db_clone = arc_db.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = handler::recv(&msg, sender_clone, db_clone).await {
+                    error!("Thread error: {:?}", e);
+                }
+            });
+        } else {
+            panic!("Received a non-text message");
+        }
+    }
+
+    info!("Shutting down");
+    registry.shutdown().await;
+    Ok(())
+}
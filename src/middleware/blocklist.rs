@@ -0,0 +1,27 @@
+use async_trait::async_trait;
+use serde_json::Value;
+use log::info;
+
+use super::{Context, Flow, Middleware};
+use crate::handler::DynErr;
+
+/// Ignores every event from the users and groups listed in config.
+pub struct Blocklist;
+
+#[async_trait]
+impl Middleware for Blocklist {
+	fn name(&self) -> &'static str {
+		"blocklist"
+	}
+
+	async fn pre(&self, event: &mut Value, ctx: &Context<'_>) -> Result<Flow, DynErr> {
+		let cfg = &ctx.config.middleware;
+		let blocked_user = event["user_id"].as_u64().is_some_and(|u| cfg.blocked_users.contains(&u));
+		let blocked_group = event["group_id"].as_u64().is_some_and(|g| cfg.blocked_groups.contains(&g));
+		if blocked_user || blocked_group {
+			info!("[blocklist] dropped event from user {} group {}", event["user_id"], event["group_id"]);
+			return Ok(Flow::Drop);
+		}
+		Ok(Flow::Continue)
+	}
+}
@@ -0,0 +1,33 @@
+use async_trait::async_trait;
+use serde_json::Value;
+
+use super::{Context, Flow, Middleware};
+use crate::handler::DynErr;
+
+/// Seconds a message ID is remembered.
+const DEDUP_TTL: u64 = 300;
+
+/// Drops messages the OneBot server delivers more than once, e.g. after a reconnect.
+pub struct Dedup;
+
+#[async_trait]
+impl Middleware for Dedup {
+	fn name(&self) -> &'static str {
+		"dedup"
+	}
+
+	async fn pre(&self, event: &mut Value, ctx: &Context<'_>) -> Result<Flow, DynErr> {
+		let Some(msg_id) = event["message_id"].as_i64() else {
+			return Ok(Flow::Continue);
+		};
+		let mut conn = ctx.db.get_multiplexed_async_connection().await?;
+		let key = format!("mw:dedup:{}:{}", event["self_id"], msg_id);
+		let first: Option<String> = redis::cmd("SET").arg(&key).arg(1).arg("NX").arg("EX").arg(DEDUP_TTL)
+			.query_async(&mut conn).await?;
+		if first.is_some() {
+			Ok(Flow::Continue)
+		} else {
+			Ok(Flow::Drop)
+		}
+	}
+}
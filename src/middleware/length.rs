@@ -0,0 +1,46 @@
+use async_trait::async_trait;
+use serde_json::Value;
+
+use super::{Context, Middleware};
+use crate::dto::RetMessage;
+use crate::handler::DynErr;
+
+/// Truncates the text of outgoing messages to `max_length` characters.
+pub struct Length;
+
+#[async_trait]
+impl Middleware for Length {
+	fn name(&self) -> &'static str {
+		"length"
+	}
+
+	async fn post(&self, _event: &Value, resp: &mut RetMessage, ctx: &Context<'_>) -> Result<(), DynErr> {
+		let Some(segments) = resp.params["message"].as_array_mut() else {
+			return Ok(());
+		};
+		let mut left = ctx.config.middleware.max_length;
+		let mut truncated = false;
+		for seg in segments.iter_mut() {
+			if seg["type"] != "text" {
+				continue;
+			}
+			let Some(text) = seg["data"]["text"].as_str() else {
+				continue;
+			};
+			let len = text.chars().count();
+			if len <= left {
+				left -= len;
+				continue;
+			}
+			let kept: String = text.chars().take(left).collect();
+			seg["data"]["text"] = Value::String(kept);
+			left = 0;
+			truncated = true;
+		}
+		if truncated {
+			segments.retain(|seg| seg["type"] != "text" || seg["data"]["text"] != "");
+			segments.push(serde_json::to_value(crate::dto::Data::string("…(truncated)".to_string()))?);
+		}
+		Ok(())
+	}
+}
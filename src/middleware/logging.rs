@@ -0,0 +1,32 @@
+use async_trait::async_trait;
+use serde_json::Value;
+use log::info;
+
+use super::{Context, Flow, Middleware};
+use crate::dto::RetMessage;
+use crate::handler::DynErr;
+
+/// Logs every incoming event and outgoing action on one line each.
+pub struct Logging;
+
+#[async_trait]
+impl Middleware for Logging {
+	fn name(&self) -> &'static str {
+		"logging"
+	}
+
+	async fn pre(&self, event: &mut Value, _ctx: &Context<'_>) -> Result<Flow, DynErr> {
+		let kind = event["message_type"].as_str()
+			.or(event["notice_type"].as_str())
+			.or(event["meta_event_type"].as_str())
+			.unwrap_or("-");
+		info!("[event {} {}] user {} group {} msg {}", event["post_type"], kind, event["user_id"], event["group_id"], event["message_id"]);
+		Ok(Flow::Continue)
+	}
+
+	async fn post(&self, event: &Value, resp: &mut RetMessage, _ctx: &Context<'_>) -> Result<(), DynErr> {
+		let segments = resp.params["message"].as_array().map(|m| m.len()).unwrap_or(0);
+		info!("[action {}] in reply to msg {}, {} segment(s)", resp.action, event["message_id"], segments);
+		Ok(())
+	}
+}
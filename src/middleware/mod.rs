@@ -0,0 +1,126 @@
+pub mod dedup;
+pub mod blocklist;
+pub mod rate_limit;
+pub mod logging;
+pub mod length;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use redis::Client;
+use serde_json::Value;
+use log::info;
+
+use crate::config::Config;
+use crate::dto::RetMessage;
+use crate::handler::DynErr;
+
+/// What to do with an event after a pre hook has seen it.
+#[derive(PartialEq)]
+pub enum Flow {
+    Continue,
+    /// Stop processing the event; no later middleware or handler sees it.
+    Drop,
+}
+
+pub struct Context<'a> {
+    pub db: Arc<Client>,
+    pub config: &'a Config,
+}
+
+/// Cross-cutting logic that runs around `handler::recv`.
+///
+/// `pre` hooks run in the order of `middleware.enabled` before the event is dispatched
+/// and may rewrite or drop it. `post` hooks run in reverse order on the outgoing reply.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn pre(&self, _event: &mut Value, _ctx: &Context<'_>) -> Result<Flow, DynErr> {
+        Ok(Flow::Continue)
+    }
+
+    async fn post(&self, _event: &Value, _resp: &mut RetMessage, _ctx: &Context<'_>) -> Result<(), DynErr> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    layers: Vec<Box<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// A pipeline holding every built-in middleware. Each still has to be enabled in config.
+    pub fn with_builtins() -> Pipeline {
+        let mut pipeline = Pipeline::new();
+        pipeline.register(Box::new(logging::Logging));
+        pipeline.register(Box::new(dedup::Dedup));
+        pipeline.register(Box::new(blocklist::Blocklist));
+        pipeline.register(Box::new(rate_limit::RateLimit));
+        pipeline.register(Box::new(length::Length));
+        pipeline
+    }
+
+    pub fn register(&mut self, middleware: Box<dyn Middleware>) -> &mut Pipeline {
+        self.layers.push(middleware);
+        self
+    }
+
+    pub async fn pre(&self, event: &mut Value, ctx: &Context<'_>) -> Result<Flow, DynErr> {
+        for m in &self.layers {
+            if m.pre(event, ctx).await? == Flow::Drop {
+                return Ok(Flow::Drop);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    pub async fn post(&self, event: &Value, resp: &mut RetMessage, ctx: &Context<'_>) -> Result<(), DynErr> {
+        for m in self.layers.iter().rev() {
+            m.post(event, resp, ctx).await?;
+        }
+        Ok(())
+    }
+}
+
+struct Installed {
+    pipeline: Pipeline,
+    config: Config,
+}
+
+static PIPELINE: OnceCell<Installed> = OnceCell::new();
+
+/// Keeps the middlewares named in `config.middleware.enabled`, in that order, and makes
+/// them the process-wide pipeline. Panics if called twice.
+pub fn install(mut pipeline: Pipeline, config: &Config) {
+    let enabled = &config.middleware.enabled;
+    pipeline.layers.retain(|m| enabled.iter().any(|n| n == m.name()));
+    pipeline.layers.sort_by_key(|m| enabled.iter().position(|n| n == m.name()));
+    for m in &pipeline.layers {
+        info!("Middleware enabled: {}", m.name());
+    }
+    if PIPELINE.set(Installed { pipeline, config: config.clone() }).is_err() {
+        panic!("Middleware pipeline already installed");
+    }
+}
+
+/// Runs the pre hooks. Without an installed pipeline every event continues.
+pub async fn pre(event: &mut Value, db: Arc<Client>) -> Result<Flow, DynErr> {
+    match PIPELINE.get() {
+        Some(p) => p.pipeline.pre(event, &Context { db, config: &p.config }).await,
+        None => Ok(Flow::Continue),
+    }
+}
+
+pub async fn post(event: &Value, resp: &mut RetMessage, db: Arc<Client>) -> Result<(), DynErr> {
+    match PIPELINE.get() {
+        Some(p) => p.pipeline.post(event, resp, &Context { db, config: &p.config }).await,
+        None => Ok(()),
+    }
+}
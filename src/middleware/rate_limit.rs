@@ -0,0 +1,60 @@
+use async_trait::async_trait;
+use redis::AsyncCommands;
+use serde_json::Value;
+use log::info;
+
+use super::{Context, Flow, Middleware};
+use crate::constants::OWNER_ID;
+use crate::handler::DynErr;
+
+/// Drops a user's messages to the bot once they send more than `rate_limit` in `rate_window`
+/// seconds. Only messages addressed to the bot count: group messages that @ it and private
+/// commands. Other chatter, which auto-join's own gate decides on, is never dropped.
+/// The owner is never limited.
+pub struct RateLimit;
+
+/// Whether `event` @-mentions the bot in a group or is a `~` command in private.
+fn addressed(event: &Value) -> bool {
+	let segments = event["message"].as_array().map(|m| m.as_slice()).unwrap_or_default();
+	if event["message_type"] == "private" {
+		let text: String = segments.iter()
+			.filter(|s| s["type"] == "text")
+			.filter_map(|s| s["data"]["text"].as_str())
+			.collect();
+		return text.starts_with('~');
+	}
+	let self_id = event["self_id"].as_u64().unwrap_or_default().to_string();
+	segments.iter().any(|s| s["type"] == "at" && s["data"]["qq"] == self_id.as_str())
+}
+
+#[async_trait]
+impl Middleware for RateLimit {
+	fn name(&self) -> &'static str {
+		"rate_limit"
+	}
+
+	async fn pre(&self, event: &mut Value, ctx: &Context<'_>) -> Result<Flow, DynErr> {
+		if event["post_type"] != "message" || !addressed(event) {
+			return Ok(Flow::Continue);
+		}
+		let Some(uid) = event["user_id"].as_u64() else {
+			return Ok(Flow::Continue);
+		};
+		if uid == *OWNER_ID.read().unwrap() {
+			return Ok(Flow::Continue);
+		}
+
+		let cfg = &ctx.config.middleware;
+		let mut conn = ctx.db.get_multiplexed_async_connection().await?;
+		let key = format!("mw:rate:{}", uid);
+		let count: u64 = conn.incr(&key, 1).await?;
+		if count == 1 {
+			let _: () = conn.expire(&key, cfg.rate_window).await?;
+		}
+		if count > cfg.rate_limit {
+			info!("[rate_limit] dropped message {} from {}", event["message_id"], uid);
+			return Ok(Flow::Drop);
+		}
+		Ok(Flow::Continue)
+	}
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+
+use crate::handler::DynErr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+	/// The token or session was rejected.
+	AuthExpired,
+	RateLimited,
+	ServerError,
+	Timeout,
+	/// The backend answered with something we couldn't parse.
+	MalformedEvent,
+	/// The circuit breaker is open; the backend wasn't asked.
+	CircuitOpen,
+	Other,
+}
+
+impl ErrorKind {
+	/// Worth trying again after a pause.
+	pub fn transient(&self) -> bool {
+		matches!(self, ErrorKind::RateLimited | ErrorKind::ServerError | ErrorKind::Timeout)
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ErrorKind::AuthExpired => "auth_expired",
+			ErrorKind::RateLimited => "rate_limited",
+			ErrorKind::ServerError => "server_error",
+			ErrorKind::Timeout => "timeout",
+			ErrorKind::MalformedEvent => "malformed_event",
+			ErrorKind::CircuitOpen => "circuit_open",
+			ErrorKind::Other => "other",
+		}
+	}
+}
+
+/// A backend failure with its kind attached.
+#[derive(Debug)]
+pub struct AiError {
+	pub kind: ErrorKind,
+	pub detail: String,
+}
+
+impl AiError {
+	pub fn boxed(kind: ErrorKind, detail: impl Into<String>) -> DynErr {
+		Box::new(AiError { kind, detail: detail.into() })
+	}
+}
+
+impl fmt::Display for AiError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}: {}", self.kind.as_str(), self.detail)
+	}
+}
+
+impl std::error::Error for AiError {}
+
+fn from_status(status: reqwest::StatusCode) -> ErrorKind {
+	match status.as_u16() {
+		401 | 403 => ErrorKind::AuthExpired,
+		429 => ErrorKind::RateLimited,
+		500..=599 => ErrorKind::ServerError,
+		_ => ErrorKind::Other,
+	}
+}
+
+fn from_reqwest(e: &reqwest::Error) -> ErrorKind {
+	if let Some(status) = e.status() {
+		from_status(status)
+	} else if e.is_timeout() {
+		ErrorKind::Timeout
+	} else if e.is_connect() || e.is_request() || e.is_body() {
+		ErrorKind::ServerError
+	} else if e.is_decode() {
+		ErrorKind::MalformedEvent
+	} else {
+		ErrorKind::Other
+	}
+}
+
+fn from_sse(e: &reqwest_eventsource::Error) -> ErrorKind {
+	use reqwest_eventsource::Error;
+	match e {
+		Error::InvalidStatusCode(status, _) => from_status(*status),
+		Error::Transport(e) => from_reqwest(e),
+		Error::StreamEnded => ErrorKind::ServerError,
+		Error::Utf8(_) | Error::Parser(_) | Error::InvalidContentType(..) | Error::InvalidLastEventId(_) => ErrorKind::MalformedEvent,
+	}
+}
+
+/// Wraps an SSE failure with its kind.
+pub fn from_eventsource(e: reqwest_eventsource::Error) -> DynErr {
+	AiError::boxed(from_sse(&e), e.to_string())
+}
+
+/// The kind of any error a backend call returned.
+pub fn classify(e: &DynErr) -> ErrorKind {
+	if let Some(e) = e.downcast_ref::<AiError>() {
+		e.kind
+	} else if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+		from_reqwest(e)
+	} else if let Some(e) = e.downcast_ref::<reqwest_eventsource::Error>() {
+		from_sse(e)
+	} else if e.downcast_ref::<serde_json::Error>().is_some() {
+		ErrorKind::MalformedEvent
+	} else {
+		ErrorKind::Other
+	}
+}
@@ -0,0 +1,223 @@
+pub mod error;
+pub mod monica;
+pub mod openai;
+pub mod vision;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use once_cell::sync::{Lazy, OnceCell};
+use redis::{AsyncCommands, Client};
+use regex::Regex;
+use serde_json::{json, Value};
+use log::info;
+
+use crate::config;
+use crate::constants::AI_DEFAULT_BACKEND;
+use crate::handler::DynErr;
+
+/// Text chunks of a reply, in order. The stream ends when the reply is complete.
+pub type ChatStream = BoxStream<'static, Result<String, DynErr>>;
+
+/// A tool the model asked to run.
+#[derive(Debug, Clone)]
+pub struct ToolCall {
+	pub id: String,
+	pub name: String,
+	pub arguments: Value,
+}
+
+/// A tool offered to the model.
+#[derive(Debug, Clone)]
+pub struct ToolSpec {
+	pub name: &'static str,
+	pub description: &'static str,
+	/// JSON schema of the arguments object.
+	pub parameters: Value,
+}
+
+/// What the model did with tools on offer.
+pub enum ChatOutcome {
+	Reply(String),
+	ToolCalls(Vec<ToolCall>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChatMessage {
+	/// `user`, `assistant` or `tool`.
+	pub role: String,
+	pub content: String,
+	/// Set on `assistant` messages that asked for tools.
+	pub tool_calls: Vec<ToolCall>,
+	/// Set on `tool` messages: the call this is the result of.
+	pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+	pub fn user(content: &str) -> ChatMessage {
+		ChatMessage { role: "user".to_string(), content: content.to_string(), ..Default::default() }
+	}
+
+	pub fn assistant_calls(calls: Vec<ToolCall>) -> ChatMessage {
+		ChatMessage { role: "assistant".to_string(), tool_calls: calls, ..Default::default() }
+	}
+
+	pub fn tool(call_id: &str, content: &str) -> ChatMessage {
+		ChatMessage {
+			role: "tool".to_string(),
+			content: content.to_string(),
+			tool_call_id: Some(call_id.to_string()),
+			..Default::default()
+		}
+	}
+}
+
+#[derive(Clone)]
+pub struct ChatRequest {
+	/// The conversation, as in the `ai:{conv}:{bot}:*` keys. See `thread::conversation`.
+	pub conv: String,
+	/// Who the request is made for, for usage accounting. 0 for the bot's own housekeeping.
+	pub user: u64,
+	pub model: String,
+	pub system: String,
+	/// Sampling temperature, if the persona sets one. Backends that can't tune it ignore it.
+	pub temperature: Option<f32>,
+	/// Oldest first; the last entry is the new user message.
+	pub messages: Vec<ChatMessage>,
+	pub db: Arc<Client>,
+}
+
+/// A service that can hold a conversation and describe images.
+#[async_trait]
+pub trait AiBackend: Send + Sync {
+	fn name(&self) -> &'static str;
+
+	/// Model used when the group hasn't selected one.
+	fn default_model(&self) -> String;
+
+	async fn chat_stream(&self, req: &ChatRequest) -> Result<ChatStream, DynErr>;
+
+	async fn chat(&self, req: &ChatRequest) -> Result<String, DynErr> {
+		let mut stream = self.chat_stream(req).await?;
+		let mut resp = String::new();
+		while let Some(chunk) = stream.next().await {
+			resp += &chunk?;
+		}
+		Ok(resp)
+	}
+
+	/// Like `chat`, with `tools` on offer.
+	///
+	/// Backends without native tool calling get the tools described in the system
+	/// prompt and are asked to answer with `<tool_call>` tags.
+	async fn chat_tools(&self, req: &ChatRequest, tools: &[ToolSpec]) -> Result<ChatOutcome, DynErr> {
+		let reply = self.chat(&prompted_request(req, tools)).await?;
+		Ok(parse_tool_calls(&reply))
+	}
+}
+
+/// `req` with the tools explained in the system prompt and earlier tool traffic turned into text.
+/// Consecutive tool results are merged into one user message, since stateful backends send only the last.
+fn prompted_request(req: &ChatRequest, tools: &[ToolSpec]) -> ChatRequest {
+	let mut req = req.clone();
+	if !tools.is_empty() {
+		let mut system = req.system.clone();
+		system += "\n你可以调用以下工具获取准确信息。需要时只输出一个或多个 <tool_call>{\"name\": 工具名, \"arguments\": {参数}}</tool_call>，不要输出其他内容；工具结果会在下一条消息给出。不需要工具时直接回答。\n";
+		for t in tools {
+			system += &format!("- {}：{} 参数：{}\n", t.name, t.description, t.parameters);
+		}
+		req.system = system;
+	}
+
+	let mut messages: Vec<ChatMessage> = Vec::with_capacity(req.messages.len());
+	for m in req.messages {
+		if m.role == "tool" {
+			let result = format!("[工具结果 {}] {}", m.tool_call_id.as_deref().unwrap_or_default(), m.content);
+			match messages.last_mut() {
+				Some(last) if last.role == "user" && last.content.starts_with("[工具结果") => {
+					last.content += "\n";
+					last.content += &result;
+				}
+				_ => messages.push(ChatMessage::user(&result)),
+			}
+		} else if !m.tool_calls.is_empty() {
+			let calls: Vec<String> = m.tool_calls.iter()
+				.map(|c| format!("<tool_call>{}</tool_call>", json!({ "name": c.name, "arguments": c.arguments })))
+				.collect();
+			messages.push(ChatMessage { role: m.role, content: m.content + calls.join("").as_str(), ..Default::default() });
+		} else {
+			messages.push(m);
+		}
+	}
+	req.messages = messages;
+	req
+}
+
+static TOOL_CALL: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)<tool_call>\s*(\{.*?\})\s*</tool_call>").unwrap());
+
+/// The `<tool_call>` tags in `reply`, or the reply itself if it has none.
+fn parse_tool_calls(reply: &str) -> ChatOutcome {
+	let calls: Vec<ToolCall> = TOOL_CALL.captures_iter(reply)
+		.filter_map(|c| serde_json::from_str::<Value>(&c[1]).ok())
+		.enumerate()
+		.filter_map(|(i, v)| Some(ToolCall {
+			id: format!("call_{}", i),
+			name: v["name"].as_str()?.to_string(),
+			arguments: v["arguments"].clone(),
+		}))
+		.collect();
+	if calls.is_empty() {
+		ChatOutcome::Reply(reply.to_string())
+	} else {
+		ChatOutcome::ToolCalls(calls)
+	}
+}
+
+static BACKENDS: OnceCell<HashMap<&'static str, Arc<dyn AiBackend>>> = OnceCell::new();
+
+/// Builds every backend that has enough configuration to run.
+pub fn init(config: &config::Ai) {
+	let mut backends: HashMap<&'static str, Arc<dyn AiBackend>> = HashMap::new();
+	let monica: Arc<dyn AiBackend> = Arc::new(monica::MonicaBackend);
+	backends.insert(monica.name(), monica);
+	if let Some(c) = &config.openai {
+		let openai: Arc<dyn AiBackend> = Arc::new(openai::OpenAiBackend::new(c));
+		backends.insert(openai.name(), openai);
+	}
+	for name in backends.keys() {
+		info!("AI backend available: {}", name);
+	}
+	let _ = BACKENDS.set(backends);
+	vision::init(config);
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn AiBackend>> {
+	BACKENDS.get()?.get(name).cloned()
+}
+
+pub fn names() -> Vec<&'static str> {
+	let mut names: Vec<&'static str> = BACKENDS.get().map(|b| b.keys().copied().collect()).unwrap_or_default();
+	names.sort();
+	names
+}
+
+/// The backend selected for `gid` with `~ai !backend`, or the configured default.
+pub async fn for_group(gid: u64, db: Arc<Client>) -> Result<Arc<dyn AiBackend>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let name: Option<String> = conn.get(format!("ai:{}:backend", gid)).await?;
+	let name = name.unwrap_or_else(|| AI_DEFAULT_BACKEND.read().unwrap().clone());
+	get(&name).ok_or_else(|| format!("AI backend {} is not configured", name).into())
+}
+
+/// Selects backend `name` for `gid`, or says why it can't.
+pub async fn set_for_group(gid: u64, db: Arc<Client>, name: &str) -> Result<Result<(), String>, DynErr> {
+	if get(name).is_none() {
+		return Ok(Err(format!("Unknown backend {}, available: {}", name, names().join(", "))));
+	}
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set(format!("ai:{}:backend", gid), name).await?;
+	Ok(Ok(()))
+}
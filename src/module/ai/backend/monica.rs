@@ -0,0 +1,296 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use redis::{AsyncCommands, Client};
+use reqwest::header::COOKIE;
+use reqwest_eventsource::{Event, EventSource};
+use tokio::time::{timeout, Duration};
+use uuid::Uuid;
+
+use super::{AiBackend, ChatRequest, ChatStream};
+use super::error::{classify, from_eventsource, AiError, ErrorKind};
+use crate::constants::{*};
+use crate::dto::{*};
+use crate::handler::DynErr;
+use crate::module::ai::bot_uid;
+
+/// Monica's private web chat API. Monica keeps the history server-side; we only
+/// track the conversation and message IDs in `ai:{conv}:{bot}:{conv,prev,now,count}`.
+pub struct MonicaBackend;
+
+#[async_trait]
+impl AiBackend for MonicaBackend {
+	fn name(&self) -> &'static str {
+		"monica"
+	}
+
+	fn default_model(&self) -> String {
+		AI_DEFAULT_MODEL.read().unwrap().clone()
+	}
+
+	async fn chat_stream(&self, req: &ChatRequest) -> Result<ChatStream, DynErr> {
+		let scope = req.conv.clone();
+		let bot = bot_uid(&req.model);
+
+		let mut conn = req.db.get_multiplexed_async_connection().await?;
+		let started: bool = conn.exists(format!("ai:{}:{}:conv", scope, bot)).await?;
+		if !started {
+			// A conversation Monica hasn't seen yet, e.g. a new thread.
+			let _: () = conn.set(format!("ai:{}:{}:conv", scope, bot), Uuid::new_v4().to_string()).await?;
+			let _: () = conn.set(format!("ai:{}:{}:prev", scope, bot), Uuid::new_v4().to_string()).await?;
+			let _: () = conn.set(format!("ai:{}:{}:now", scope, bot), Uuid::new_v4().to_string()).await?;
+			let _: () = conn.set(format!("ai:{}:{}:count", scope, bot), 0).await?;
+		}
+		let conv: String = conn.get(format!("ai:{}:{}:conv", scope, bot)).await?;
+		let prev: String = conn.get(format!("ai:{}:{}:prev", scope, bot)).await?;
+		let now: String = conn.get(format!("ai:{}:{}:now", scope, bot)).await?;
+		let count: i32 = conn.get(format!("ai:{}:{}:count", scope, bot)).await?;
+
+		let next_msg = Uuid::new_v4().to_string();
+
+		let mut items = Vec::new();
+
+		// Monica remembers earlier turns itself, so only the newest message is sent.
+		// It has no system role, so the system prompt leads every question.
+		let mut msg = req.messages.last().map(|m| m.content.clone()).unwrap_or_default();
+		if !req.system.is_empty() {
+			msg = format!("[{}]\n{}", req.system, msg);
+		}
+		if count == 0 {
+			items.push(ConversationItem {
+				item_id: "msg:".to_owned()+prev.as_str(),
+				conversation_id: "conv:".to_owned()+conv.as_str(),
+				item_type: "reply".to_string(),
+				summary: "__RENDER_BOT_WELCOME_MSG__".to_string(),
+				parent_item_id: None,
+				data: ItemData {
+					data_type: "text".to_string(),
+					content: "__RENDER_BOT_WELCOME_MSG__".to_string(),
+					quote_content: None,
+					max_token: None,
+					is_incognito: None,
+					file_infos: None,
+				},
+			});
+		}
+
+		items.push(ConversationItem {
+			item_id: "msg:".to_owned()+now.as_str(),
+			conversation_id: "conv:".to_owned()+conv.as_str(),
+			item_type: "question".to_string(),
+			summary: msg.to_string(),
+			parent_item_id: Some("msg:".to_owned()+prev.as_str()),
+			data: ItemData {
+				data_type: "text".to_string(),
+				content: msg.to_string(),
+				quote_content: Some("".to_string()),
+				max_token: Some(0),
+				is_incognito: Some(false),
+				file_infos: None,
+			},
+		});
+
+		let data = ChatData {
+			task_uid: "task:".to_owned()+Uuid::new_v4().to_string().as_str(),
+			bot_uid: bot.clone(),
+			data: ConversationData {
+				conversation_id: "conv:".to_owned()+conv.as_str(),
+				items,
+				pre_generated_reply_id: "msg:".to_owned()+next_msg.as_str(),
+				pre_parent_item_id: "msg:".to_owned()+now.as_str(),
+				origin: "https://monica.im/home/chat/DeepSeek%20V3/deepseek_chat",
+				origin_page_title: "o3-mini - Monica 智能体",
+				trigger_by: "auto",
+				use_model: req.model.clone(),
+				is_incognito: false,
+				use_new_memory: true,
+			},
+			language: "auto",
+			locale: "zh_CN",
+			task_type: "chat",
+			tool_data: ToolData {
+				sys_skill_list: vec![],
+			},
+			ai_resp_language: "Chinese (Simplified)",
+		};
+
+		let mut upstream = stream_request(&data)?;
+		let (tx, rx) = mpsc::unbounded();
+		let db = req.db.clone();
+		tokio::spawn(async move {
+			while let Some(chunk) = upstream.next().await {
+				let failed = chunk.is_err();
+				let _ = tx.unbounded_send(chunk);
+				if failed {
+					return;
+				}
+			}
+			// Only move the chain forward once Monica has the whole exchange.
+			if let Err(e) = advance(&scope, &bot, next_msg, db).await {
+				let _ = tx.unbounded_send(Err(e));
+			}
+		});
+		Ok(rx.boxed())
+	}
+}
+
+async fn advance(scope: &str, bot: &str, next_msg: String, db: Arc<Client>) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set(format!("ai:{}:{}:prev", scope, bot), next_msg).await?;
+	let _: () = conn.set(format!("ai:{}:{}:now", scope, bot), Uuid::new_v4().to_string()).await?;
+	let _: () = conn.incr(format!("ai:{}:{}:count", scope, bot), 1).await?;
+	Ok(())
+}
+
+/// One pooled client for every Monica request. The session cookie is added per
+/// request, so `~ai !token` takes effect without rebuilding it.
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// Cheapest authenticated call we already make: asking for an upload URL.
+/// Monica has no documented session endpoint.
+const SESSION_CHECK_URL: &str = "https://api.monica.im/api/file_object/pre_sign_list_by_module";
+
+/// The shared client, also used for Monica's file uploads.
+pub fn client() -> &'static reqwest::Client {
+	&CLIENT
+}
+
+/// `builder` with the current `session_id` cookie.
+pub fn authorized(builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+	builder.header(COOKIE, format!("session_id={}", AI_TOKEN.read().unwrap().as_str()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Session {
+	Unknown,
+	Valid,
+	Expired,
+}
+
+static SESSION: AtomicU8 = AtomicU8::new(0);
+
+pub fn session() -> Session {
+	match SESSION.load(Ordering::Relaxed) {
+		1 => Session::Valid,
+		2 => Session::Expired,
+		_ => Session::Unknown,
+	}
+}
+
+fn set_session(s: Session) {
+	let old = SESSION.swap(s as u8, Ordering::Relaxed);
+	if s == Session::Expired && old != Session::Expired as u8 {
+		warn!("Monica session expired, set a new one with ~ai !token in private");
+	}
+}
+
+/// Asks Monica whether the current session is accepted.
+pub async fn check_session() -> Result<Session, DynErr> {
+	let resp = authorized(client().post(SESSION_CHECK_URL))
+		.json(&serde_json::json!({
+			"filename_list": ["check.png"],
+			"location": "files",
+			"module": "chat_bot",
+			"obj_id": Uuid::new_v4().simple().to_string(),
+		}))
+		.send()
+		.await?;
+	let s = match resp.status().as_u16() {
+		401 | 403 => Session::Expired,
+		_ if resp.status().is_success() => Session::Valid,
+		code => return Err(format!("Unexpected status {} checking the Monica session", code).into()),
+	};
+	set_session(s);
+	Ok(s)
+}
+
+/// Uses the token saved by `~ai !token`, if any, instead of the configured one.
+pub async fn load_token(db: Arc<Client>) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let token: Option<String> = conn.get("ai:monica:token").await?;
+	if let Some(token) = token {
+		set_ai_token(token);
+		info!("Using the Monica session set with ~ai !token");
+	}
+	Ok(())
+}
+
+/// Switches to `token` and keeps it across restarts.
+pub async fn rotate_token(db: Arc<Client>, token: &str) -> Result<Session, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set("ai:monica:token", token).await?;
+	set_ai_token(token.to_string());
+	check_session().await
+}
+
+fn event_source(req: &ChatData) -> Result<EventSource, DynErr> {
+    let request_url = reqwest::Url::parse(AI_ENDPOINT.read().unwrap().as_str())?;
+    let request = authorized(client().post(request_url))
+        .json(&req);
+
+    // Use server-sent events to receive response
+    Ok(EventSource::new(request)?)
+}
+
+/// Sends `req` and yields the reply text as Monica streams it.
+pub fn stream_request(req: &ChatData) -> Result<ChatStream, DynErr> {
+    let mut event_source = event_source(req)?;
+    let (tx, rx) = mpsc::unbounded();
+
+    tokio::spawn(async move {
+        loop {
+            let event = match timeout(Duration::from_secs(30), event_source.next()).await {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(_) => {
+                    let _ = tx.unbounded_send(Err(AiError::boxed(ErrorKind::Timeout, "No event for 30s")));
+                    break;
+                }
+            };
+            match event {
+                Ok(Event::Open) => set_session(Session::Valid),
+                Ok(Event::Message(message)) => {
+                    let v: serde_json::Value = match serde_json::from_str(&message.data) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            let _ = tx.unbounded_send(Err(AiError::boxed(ErrorKind::MalformedEvent, e.to_string())));
+                            break;
+                        }
+                    };
+                    if let Some(text) = v["text"].as_str().filter(|t| !t.is_empty()) {
+                        if tx.unbounded_send(Ok(text.to_string())).is_err() {
+                            break;
+                        }
+                    }
+                    if v.get("finished").and_then(|b| b.as_bool()).unwrap_or(false) {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    let e = from_eventsource(e);
+                    if classify(&e) == ErrorKind::AuthExpired {
+                        set_session(Session::Expired);
+                    }
+                    let _ = tx.unbounded_send(Err(e));
+                    break;
+                }
+            }
+        }
+        event_source.close();
+    });
+    Ok(rx.boxed())
+}
+
+pub async fn send_request(req: &ChatData) -> Result<String, DynErr> {
+    let mut stream = stream_request(req)?;
+    let mut resp = String::new();
+    while let Some(chunk) = stream.next().await {
+        resp += &chunk?;
+    }
+    Ok(resp)
+}
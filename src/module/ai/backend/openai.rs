@@ -0,0 +1,166 @@
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use reqwest_eventsource::{Event, EventSource};
+use serde_json::{json, Value};
+use tokio::time::{timeout, Duration};
+
+use super::{AiBackend, ChatOutcome, ChatRequest, ChatStream, ToolCall, ToolSpec};
+use super::error::{from_eventsource, AiError, ErrorKind};
+use crate::config;
+use crate::handler::DynErr;
+
+/// Any server implementing OpenAI's `/v1/chat/completions`: OpenAI itself,
+/// llama.cpp's server, vLLM, Ollama and so on. Stateless: every request
+/// carries the messages it should see.
+pub struct OpenAiBackend {
+	endpoint: String,
+	token: String,
+	default_model: String,
+	client: reqwest::Client,
+}
+
+impl OpenAiBackend {
+	pub fn new(config: &config::OpenAi) -> OpenAiBackend {
+		OpenAiBackend {
+			endpoint: config.endpoint.trim_end_matches('/').to_string(),
+			token: config.token.clone(),
+			default_model: config.default_model.clone(),
+			// Bounds every request, streamed ones included, so a stalled server can't hold
+			// the conversation lock forever.
+			client: reqwest::Client::builder().timeout(Duration::from_secs(180)).build().unwrap_or_default(),
+		}
+	}
+
+	fn post(&self, path: &str, body: &Value) -> reqwest::RequestBuilder {
+		let request = self.client.post(format!("{}{}", self.endpoint, path)).json(body);
+		if self.token.is_empty() {
+			request
+		} else {
+			request.bearer_auth(&self.token)
+		}
+	}
+}
+
+fn messages(req: &ChatRequest) -> Vec<Value> {
+	let mut out = Vec::with_capacity(req.messages.len() + 1);
+	if !req.system.is_empty() {
+		out.push(json!({ "role": "system", "content": req.system }));
+	}
+	for m in &req.messages {
+		if !m.tool_calls.is_empty() {
+			let calls: Vec<Value> = m.tool_calls.iter().map(|c| json!({
+				"id": c.id,
+				"type": "function",
+				"function": { "name": c.name, "arguments": c.arguments.to_string() },
+			})).collect();
+			out.push(json!({ "role": m.role, "content": m.content, "tool_calls": calls }));
+		} else if let Some(id) = &m.tool_call_id {
+			out.push(json!({ "role": m.role, "content": m.content, "tool_call_id": id }));
+		} else {
+			out.push(json!({ "role": m.role, "content": m.content }));
+		}
+	}
+	out
+}
+
+#[async_trait]
+impl AiBackend for OpenAiBackend {
+	fn name(&self) -> &'static str {
+		"openai"
+	}
+
+	fn default_model(&self) -> String {
+		self.default_model.clone()
+	}
+
+	async fn chat_stream(&self, req: &ChatRequest) -> Result<ChatStream, DynErr> {
+		let mut body = json!({
+			"model": req.model,
+			"messages": messages(req),
+			"stream": true,
+		});
+		if let Some(t) = req.temperature {
+			body["temperature"] = json!(t);
+		}
+		let mut event_source = EventSource::new(self.post("/chat/completions", &body))?;
+		let (tx, rx) = mpsc::unbounded();
+
+		tokio::spawn(async move {
+			loop {
+				let event = match timeout(Duration::from_secs(30), event_source.next()).await {
+					Ok(Some(event)) => event,
+					Ok(None) => break,
+					Err(_) => {
+						let _ = tx.unbounded_send(Err(AiError::boxed(ErrorKind::Timeout, "No event for 30s")));
+						break;
+					}
+				};
+				match event {
+					Ok(Event::Open) => {},
+					Ok(Event::Message(message)) => {
+						if message.data.trim() == "[DONE]" {
+							break;
+						}
+						let v: Value = match serde_json::from_str(&message.data) {
+							Ok(v) => v,
+							Err(e) => {
+								let _ = tx.unbounded_send(Err(AiError::boxed(ErrorKind::MalformedEvent, e.to_string())));
+								break;
+							}
+						};
+						if let Some(text) = v["choices"][0]["delta"]["content"].as_str().filter(|t| !t.is_empty()) {
+							if tx.unbounded_send(Ok(text.to_string())).is_err() {
+								break;
+							}
+						}
+						if v["choices"][0]["finish_reason"].is_string() {
+							break;
+						}
+					},
+					Err(e) => {
+						let _ = tx.unbounded_send(Err(from_eventsource(e)));
+						break;
+					}
+				}
+			}
+			event_source.close();
+		});
+		Ok(rx.boxed())
+	}
+
+	async fn chat_tools(&self, req: &ChatRequest, tools: &[ToolSpec]) -> Result<ChatOutcome, DynErr> {
+		let mut body = json!({
+			"model": req.model,
+			"messages": messages(req),
+		});
+		if !tools.is_empty() {
+			body["tools"] = tools.iter().map(|t| json!({
+				"type": "function",
+				"function": { "name": t.name, "description": t.description, "parameters": t.parameters },
+			})).collect();
+		}
+		if let Some(t) = req.temperature {
+			body["temperature"] = json!(t);
+		}
+		let resp = self.post("/chat/completions", &body).send().await?.error_for_status()?;
+		let v: Value = resp.json().await?;
+		let message = &v["choices"][0]["message"];
+		let calls: Vec<ToolCall> = message["tool_calls"].as_array().into_iter().flatten()
+			.filter_map(|c| Some(ToolCall {
+				id: c["id"].as_str()?.to_string(),
+				name: c["function"]["name"].as_str()?.to_string(),
+				// Arguments arrive as a JSON string; a malformed one becomes an empty object.
+				arguments: c["function"]["arguments"].as_str()
+					.and_then(|a| serde_json::from_str(a).ok())
+					.unwrap_or_else(|| json!({})),
+			}))
+			.collect();
+		if !calls.is_empty() {
+			return Ok(ChatOutcome::ToolCalls(calls));
+		}
+		message["content"].as_str()
+			.map(|s| ChatOutcome::Reply(s.to_string()))
+			.ok_or_else(|| AiError::boxed(ErrorKind::MalformedEvent, format!("Unexpected chat response: {}", v)))
+	}
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use redis::Client;
+use serde_json::{json, Value};
+use tokio::time::{timeout, Duration};
+use log::{info, warn};
+
+use super::error::{AiError, ErrorKind};
+use crate::config;
+use crate::dto::ImgData;
+use crate::handler::DynErr;
+use crate::module::ai::models;
+use crate::module::ai::retry::Resilient;
+use crate::module::ai::usage::{self, Metered};
+use crate::module::ai_img::{download_at_most, process_image};
+
+/// One image to describe, and whose turn it is, for usage accounting.
+pub struct VisionRequest<'a> {
+	pub img: &'a ImgData,
+	pub prompt: &'a str,
+	/// 0 in private.
+	pub gid: u64,
+	pub user: u64,
+	pub db: Arc<Client>,
+}
+
+/// Something that can describe an image in text.
+#[async_trait]
+pub trait VisionBackend: Send + Sync {
+	fn name(&self) -> &'static str;
+
+	/// The model that looks at the images.
+	fn model(&self) -> String;
+
+	/// Describes `req.img` in text, following `req.prompt`.
+	async fn describe(&self, req: &VisionRequest<'_>) -> Result<String, DynErr>;
+}
+
+/// Uploads the image to Monica and asks its Gemini bot.
+pub struct MonicaVision {
+	wait: Duration,
+	max_bytes: usize,
+}
+
+#[async_trait]
+impl VisionBackend for MonicaVision {
+	fn name(&self) -> &'static str {
+		"monica"
+	}
+
+	fn model(&self) -> String {
+		"gemini-2.0".to_string()
+	}
+
+	async fn describe(&self, req: &VisionRequest<'_>) -> Result<String, DynErr> {
+		process_image(req.img, req.prompt, self.wait, self.max_bytes).await
+	}
+}
+
+/// Sends the image inline, as a base64 data URL, to an OpenAI-compatible vision model.
+/// Works with servers that can't fetch QQ's image URLs themselves.
+pub struct InlineVision {
+	endpoint: String,
+	token: String,
+	model: String,
+	max_bytes: usize,
+	client: reqwest::Client,
+}
+
+/// The MIME type of an image, from its first bytes.
+fn mime(bytes: &[u8]) -> &'static str {
+	match bytes {
+		[0x89, b'P', b'N', b'G', ..] => "image/png",
+		[b'G', b'I', b'F', ..] => "image/gif",
+		[b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+		_ => "image/jpeg",
+	}
+}
+
+#[async_trait]
+impl VisionBackend for InlineVision {
+	fn name(&self) -> &'static str {
+		"openai"
+	}
+
+	fn model(&self) -> String {
+		self.model.clone()
+	}
+
+	async fn describe(&self, req: &VisionRequest<'_>) -> Result<String, DynErr> {
+		let (img, prompt) = (req.img, req.prompt);
+		let bytes = download_at_most(&self.client, &img.url, self.max_bytes).await?;
+		let url = format!("data:{};base64,{}", mime(&bytes), base64::engine::general_purpose::STANDARD.encode(&bytes));
+		let body = json!({
+			"model": self.model,
+			"messages": [{
+				"role": "user",
+				"content": [
+					{ "type": "text", "text": prompt },
+					{ "type": "image_url", "image_url": { "url": url } },
+				],
+			}],
+		});
+		let mut request = self.client.post(format!("{}/chat/completions", self.endpoint)).json(&body);
+		if !self.token.is_empty() {
+			request = request.bearer_auth(&self.token);
+		}
+		let v: Value = request.send().await?.error_for_status()?.json().await?;
+		v["choices"][0]["message"]["content"].as_str()
+			.map(|s| s.to_string())
+			.ok_or_else(|| AiError::boxed(ErrorKind::MalformedEvent, format!("Unexpected vision response: {}", v)))
+	}
+}
+
+struct Vision {
+	backends: HashMap<&'static str, Arc<dyn VisionBackend>>,
+	/// `None` follows the group's chat backend.
+	fixed: Option<String>,
+	prompt: String,
+	timeout: Duration,
+}
+
+static VISION: OnceCell<Vision> = OnceCell::new();
+
+pub fn init(config: &config::Ai) {
+	let v = &config.vision;
+	let wait = Duration::from_secs(v.timeout_secs);
+	let max_bytes = v.max_kb as usize * 1024;
+	let mut backends: HashMap<&'static str, Arc<dyn VisionBackend>> = HashMap::new();
+	backends.insert("monica", Arc::new(Metered(Arc::new(Resilient(Arc::new(MonicaVision { wait, max_bytes }))))));
+	let endpoint = v.endpoint.clone().or_else(|| config.openai.as_ref().map(|o| o.endpoint.clone()));
+	// Only a model known to take images: one named for vision in config, else the first
+	// catalogued with `vision = true`. Text-only models never see an image.
+	let model = v.model.clone()
+		.or_else(|| config.openai.as_ref().and_then(|o| o.vision_model.clone()))
+		.or_else(|| models::all().iter().find(|m| m.vision && m.backend == "openai").map(|m| m.name.clone()));
+	if let (Some(endpoint), Some(model)) = (endpoint, model) {
+		let token = v.token.clone().or_else(|| config.openai.as_ref().map(|o| o.token.clone())).unwrap_or_default();
+		info!("Vision model available: {}", model);
+		backends.insert("openai", Arc::new(Metered(Arc::new(Resilient(Arc::new(InlineVision {
+			endpoint: endpoint.trim_end_matches('/').to_string(),
+			token,
+			model,
+			max_bytes,
+			client: reqwest::Client::builder().timeout(wait).build().unwrap_or_default(),
+		}))))));
+	}
+	if let Some(name) = v.backend.as_deref().filter(|n| !backends.contains_key(n)) {
+		warn!("Vision backend {} is not configured, following the chat backend instead", name);
+	}
+	let fixed = v.backend.clone().filter(|n| backends.contains_key(n.as_str()));
+	let _ = VISION.set(Vision { backends, fixed, prompt: v.prompt.clone(), timeout: wait });
+}
+
+/// What `img`, sent by `user` in `gid`, shows, as the vision backend configured or
+/// matching `gid`'s chat backend sees it. The description counts towards the day's usage.
+/// Stickers, failures and used-up quotas fall back to the image's own summary.
+pub async fn describe(gid: u64, user: u64, db: Arc<Client>, img: &ImgData) -> String {
+	let Some(v) = VISION.get() else {
+		return img.summary.clone();
+	};
+	if img.url.is_empty() || !matches!(usage::over_quota(gid, user, db.clone()).await, Ok(None)) {
+		return img.summary.clone();
+	}
+	let name = match &v.fixed {
+		Some(name) => name.clone(),
+		None => match super::for_group(gid, db.clone()).await {
+			Ok(b) => b.name().to_string(),
+			Err(_) => String::new(),
+		},
+	};
+	let Some(backend) = v.backends.get(name.as_str()).or_else(|| v.backends.get("monica")) else {
+		return img.summary.clone();
+	};
+	// Monica's upload waits out its own deadline; leave it a little headroom.
+	let req = VisionRequest { img, prompt: &v.prompt, gid, user, db };
+	match timeout(v.timeout + Duration::from_secs(15), backend.describe(&req)).await {
+		Ok(Ok(text)) => text,
+		Ok(Err(e)) => {
+			warn!("{} could not describe {}: {:?}", backend.name(), img.file, e);
+			img.summary.clone()
+		}
+		Err(_) => {
+			warn!("{} took too long to describe {}", backend.name(), img.file);
+			img.summary.clone()
+		}
+	}
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use redis::Client;
+use serde_json::json;
+use log::{error, info};
+
+use super::{moderation, template, usage};
+use crate::config::{self, Config};
+use crate::constants::OWNER_ID;
+use crate::dto::Data;
+use crate::handler::DynErr;
+use crate::plugin::{MessageContext, Plugin};
+
+/// An OpenAI-compatible `/images/generations` API.
+struct Painter {
+	endpoint: String,
+	token: String,
+	model: String,
+	size: String,
+	quota_user_daily: u64,
+	client: reqwest::Client,
+}
+
+static PAINTER: OnceCell<Painter> = OnceCell::new();
+
+pub fn init(config: &config::Ai) {
+	let d = &config.draw;
+	let endpoint = d.endpoint.clone().or_else(|| config.openai.as_ref().map(|o| o.endpoint.clone()));
+	let Some(endpoint) = endpoint else {
+		return;
+	};
+	let token = d.token.clone().or_else(|| config.openai.as_ref().map(|o| o.token.clone())).unwrap_or_default();
+	let _ = PAINTER.set(Painter {
+		endpoint: endpoint.trim_end_matches('/').to_string(),
+		token,
+		model: d.model.clone(),
+		size: d.size.clone(),
+		quota_user_daily: d.quota_user_daily,
+		client: reqwest::Client::builder().timeout(Duration::from_secs(180)).build().unwrap_or_default(),
+	});
+}
+
+pub fn enabled() -> bool {
+	PAINTER.get().is_some()
+}
+
+impl Painter {
+	/// The image for `prompt`, as a file value for `Data::image`.
+	async fn generate(&self, prompt: &str) -> Result<String, DynErr> {
+		let mut request = self.client.post(format!("{}/images/generations", self.endpoint))
+			.json(&json!({ "model": self.model, "prompt": prompt, "n": 1, "size": self.size }));
+		if !self.token.is_empty() {
+			request = request.bearer_auth(&self.token);
+		}
+		let v: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+		let image = &v["data"][0];
+		if let Some(url) = image["url"].as_str() {
+			Ok(url.to_string())
+		} else if let Some(b64) = image["b64_json"].as_str() {
+			Ok(format!("base64://{}", b64))
+		} else {
+			Err(format!("No image in the response: {}", v).into())
+		}
+	}
+}
+
+/// Whether the sender's images count against the daily quota.
+fn limited(painter: &Painter, user: u64) -> bool {
+	painter.quota_user_daily > 0 && user != *OWNER_ID.read().unwrap()
+}
+
+/// What users are told when they drew all they may today, unless a template replaces it.
+pub fn quota_message() -> &'static str {
+	if template::english() {
+		"You have drawn all the images you may today. Come back tomorrow!"
+	} else {
+		"你今天的画图次数已经用完了，明天再来吧"
+	}
+}
+
+/// Draws `prompt` for `ctx`'s sender: the image, or a refusal to show instead.
+pub async fn draw(ctx: &MessageContext, prompt: &str) -> Result<Result<Data, String>, DynErr> {
+	let Some(painter) = PAINTER.get() else {
+		return Ok(Err("Image generation is not configured".to_string()));
+	};
+	let prompt = match moderation::check(ctx, moderation::Kind::Prompt, prompt).await? {
+		moderation::Verdict::Allow(prompt) => prompt,
+		moderation::Verdict::Refuse(refusal) => return Ok(Err(refusal)),
+	};
+	let gid = ctx.group_id.unwrap_or_default();
+	// Counted before generating, so concurrent draws can't all slip under the quota,
+	// and taken back when refused or when generation fails.
+	let used = usage::record_image(gid, ctx.user_id, &painter.model, ctx.db.clone(), 1).await?;
+	if limited(painter, ctx.user_id) && used > painter.quota_user_daily {
+		usage::record_image(gid, ctx.user_id, &painter.model, ctx.db.clone(), -1).await?;
+		let t = template::get_or(gid, ctx.db.clone(), "draw.quota", quota_message()).await?;
+		return Ok(Err(template::render(&t, &template::vars(ctx).await)));
+	}
+	info!("[{} =>draw] {}", ctx.msg_id, prompt);
+	match painter.generate(&prompt).await {
+		Ok(image) => Ok(Ok(Data::image(image))),
+		Err(e) => {
+			if let Err(e) = usage::record_image(gid, ctx.user_id, &painter.model, ctx.db.clone(), -1).await {
+				error!("[{} =>draw] failed to give back the image quota: {:?}", ctx.msg_id, e);
+			}
+			Err(e)
+		}
+	}
+}
+
+/// `~draw <prompt>`
+pub struct DrawPlugin;
+
+#[async_trait]
+impl Plugin for DrawPlugin {
+	fn name(&self) -> &'static str {
+		"draw"
+	}
+
+	fn commands(&self) -> Vec<&'static str> {
+		vec!["draw"]
+	}
+
+	async fn on_load(&self, config: &Config, _db: Arc<Client>) -> Result<(), DynErr> {
+		init(&config.ai);
+		Ok(())
+	}
+
+	async fn on_command(&self, ctx: &MessageContext, cmd: &str, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+		if cmd != "draw" {
+			return Ok(None);
+		}
+		if args.is_empty() {
+			return Ok(Some(vec![Data::string("Usage: ~draw <prompt>".to_string())]));
+		}
+		let ret = match draw(ctx, &args.join(" ")).await {
+			Ok(Ok(image)) => image,
+			Ok(Err(refusal)) => Data::string(refusal),
+			Err(e) => return Ok(Some(super::retry::friendly(ctx, e).await)),
+		};
+		Ok(Some(vec![Data::reply(ctx.msg_id), ret]))
+	}
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use redis::{AsyncCommands, Client};
+use log::info;
+
+use super::backend::{ChatMessage, ChatRequest};
+use crate::config;
+use crate::handler::DynErr;
+use crate::plugin::MessageContext;
+
+/// What the model answers with when an unasked message needs no reply.
+pub const NO_REPLY: &str = "[NO_REPLY]";
+
+/// Per-group settings accepted by `~ai !join`.
+pub const FIELDS: [&str; 3] = ["probability", "cooldown", "rate"];
+
+const CLASSIFIER_PROMPT: &str = "下面是群聊里的一条消息，没有人@你。判断你是否应该主动回复：只有当消息在向大家提问、与你有关，或者你能提供有价值的信息时才回复。只回答 yes 或 no。\n消息：";
+
+static CONFIG: OnceCell<config::Join> = OnceCell::new();
+
+pub fn init(config: &config::Join) {
+	let _ = CONFIG.set(config.clone());
+}
+
+fn config() -> config::Join {
+	CONFIG.get().cloned().unwrap_or_default()
+}
+
+/// Appended to the system prompt of unasked turns.
+pub fn hint() -> String {
+	format!("\n这条消息不是发给你的。如果不值得回复，只输出 {}，不要输出其他内容。", NO_REPLY)
+}
+
+/// Whether the model chose to stay silent.
+pub fn is_no_reply(reply: &str) -> bool {
+	reply.trim().is_empty() || reply.contains(NO_REPLY)
+}
+
+/// The group's settings: its `~ai !join` overrides over the config.
+pub async fn settings(gid: u64, db: Arc<Client>) -> Result<config::Join, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let over: HashMap<String, String> = conn.hgetall(format!("ai:{}:join:cfg", gid)).await?;
+	let mut c = config();
+	if let Some(p) = over.get("probability").and_then(|v| v.parse().ok()) {
+		c.probability = p;
+	}
+	if let Some(s) = over.get("cooldown").and_then(|v| v.parse().ok()) {
+		c.cooldown = s;
+	}
+	if let Some(r) = over.get("rate").and_then(|v| v.parse().ok()) {
+		c.rate = r;
+	}
+	Ok(c)
+}
+
+/// Overrides one auto-join setting for `gid`, or says what is wrong with it.
+pub async fn set_field(gid: u64, db: Arc<Client>, field: &str, value: &str) -> Result<Result<(), String>, DynErr> {
+	let valid = match field {
+		"probability" => value.parse::<f64>().is_ok_and(|p| (0.0..=1.0).contains(&p)),
+		"cooldown" | "rate" => value.parse::<u64>().is_ok(),
+		_ => return Ok(Err(format!("Unknown field {}, expected one of {}", field, FIELDS.join(", ")))),
+	};
+	if !valid {
+		return Ok(Err(format!("Invalid value {} for {}", value, field)));
+	}
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.hset(format!("ai:{}:join:cfg", gid), field, value).await?;
+	Ok(Ok(()))
+}
+
+/// Whether the bot should answer `ctx`, a group message nobody addressed to it.
+/// `text` is what the message says, voice included.
+///
+/// Checked in order: the reply-rate cap, the cooldown, keywords (which skip the
+/// dice), the probability roll, then the classifier if enabled.
+pub async fn should_reply(ctx: &MessageContext, text: &str) -> Result<bool, DynErr> {
+	let gid = ctx.group_id.ok_or("Auto-join needs a group")?;
+	let c = settings(gid, ctx.db.clone()).await?;
+	let mut conn = ctx.db.get_multiplexed_async_connection().await?;
+
+	if c.rate > 0 {
+		let count: Option<u64> = conn.get(format!("ai:{}:join:count", gid)).await?;
+		if count.unwrap_or_default() >= c.rate {
+			return Ok(false);
+		}
+	}
+	if conn.exists(format!("ai:{}:join:cooldown", gid)).await? {
+		return Ok(false);
+	}
+	if !c.keywords.iter().any(|k| !k.is_empty() && text.contains(k.as_str())) {
+		let roll: f64 = rand::rng().random();
+		if roll >= c.probability {
+			return Ok(false);
+		}
+	}
+	if c.classifier && !classify(ctx, gid, text).await? {
+		info!("[{} {gid}] =>ai_gate] classifier said no", ctx.msg_id);
+		return Ok(false);
+	}
+	Ok(true)
+}
+
+/// Asks the group's model whether the message deserves an answer. Each question gets a
+/// conversation of its own, dropped after the verdict, so earlier verdicts don't sway it.
+async fn classify(ctx: &MessageContext, gid: u64, text: &str) -> Result<bool, DynErr> {
+	let (backend, _, model) = super::setup(gid, ctx.db.clone()).await?;
+	let conv = format!("{}:gate:{}", gid, ctx.msg_id);
+	let bot = super::bot_uid(&model);
+	let req = ChatRequest {
+		conv: conv.clone(),
+		user: ctx.user_id,
+		model,
+		system: String::new(),
+		temperature: Some(0.0),
+		messages: vec![ChatMessage::user(&format!("{}{}", CLASSIFIER_PROMPT, text))],
+		db: ctx.db.clone(),
+	};
+	let answer = backend.chat(&req).await;
+	super::drop_record(&conv, ctx.db.clone(), &bot).await?;
+	Ok(verdict(&answer?))
+}
+
+/// Whether the classifier said yes. Only a bare `yes` or `是` counts; anything else,
+/// "不是" and "not yes" included, is a no.
+fn verdict(answer: &str) -> bool {
+	let answer = answer.trim().trim_end_matches(['.', '。', '!', '！']).trim();
+	answer.eq_ignore_ascii_case("yes") || answer == "是"
+}
+
+/// Starts the cooldown and counts the reply against the rate.
+pub async fn replied(gid: u64, db: Arc<Client>) -> Result<(), DynErr> {
+	let c = settings(gid, db.clone()).await?;
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	if c.cooldown > 0 {
+		let _: () = conn.set_ex(format!("ai:{}:join:cooldown", gid), 1, c.cooldown).await?;
+	}
+	let key = format!("ai:{}:join:count", gid);
+	let count: u64 = conn.incr(&key, 1).await?;
+	if count == 1 {
+		let _: () = conn.expire(&key, c.rate_window as i64).await?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn verdict_accepts_a_bare_yes() {
+		assert!(verdict("yes"));
+		assert!(verdict(" Yes.\n"));
+		assert!(verdict("是"));
+		assert!(verdict("是。"));
+	}
+
+	#[test]
+	fn verdict_rejects_everything_else() {
+		assert!(!verdict("no"));
+		assert!(!verdict("否"));
+		assert!(!verdict("不是"));
+		assert!(!verdict("not yes"));
+		assert!(!verdict("yes, but no"));
+		assert!(!verdict(""));
+	}
+
+	#[test]
+	fn no_reply_marker() {
+		assert!(is_no_reply("  "));
+		assert!(is_no_reply("[NO_REPLY]"));
+		assert!(!is_no_reply("好的"));
+	}
+}
@@ -0,0 +1,108 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+
+use super::backend::ChatMessage;
+use crate::constants::{AI_CONTEXT_BUDGET, AI_CONTEXT_TOKENS, AI_HISTORY_LEN};
+use crate::handler::DynErr;
+
+/// One message of a conversation as stored in `ai:{conv}:{bot}:history`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Turn {
+	/// `user` or `assistant`.
+	pub role: String,
+	pub speaker: String,
+	/// Unix seconds.
+	pub ts: u64,
+	pub content: String,
+}
+
+impl Turn {
+	pub fn new(role: &str, speaker: &str, content: &str) -> Turn {
+		Turn {
+			role: role.to_string(),
+			speaker: speaker.to_string(),
+			ts: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+			content: content.to_string(),
+		}
+	}
+}
+
+/// `conv` as returned by `thread::conversation`.
+pub fn key(conv: &str, bot: &str) -> String {
+	format!("ai:{}:{}:history", conv, bot)
+}
+
+/// Appends `turns` and drops the oldest ones beyond `history_len`.
+pub async fn push(db: Arc<Client>, key: &str, turns: &[Turn]) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let mut encoded = Vec::with_capacity(turns.len());
+	for t in turns {
+		encoded.push(serde_json::to_string(t)?);
+	}
+	let _: () = conn.rpush(key, encoded).await?;
+	let len = *AI_HISTORY_LEN.read().unwrap();
+	let _: () = conn.ltrim(key, -len, -1).await?;
+	Ok(())
+}
+
+/// The whole history, oldest first. Entries that fail to parse are skipped.
+pub async fn load(db: Arc<Client>, key: &str) -> Result<Vec<Turn>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let raw: Vec<String> = conn.lrange(key, 0, -1).await?;
+	Ok(raw.iter().filter_map(|r| serde_json::from_str(r).ok()).collect())
+}
+
+/// Removes the newest `n` turns.
+pub async fn pop(db: Arc<Client>, key: &str, n: usize) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.ltrim(key, 0, -(n as isize) - 1).await?;
+	Ok(())
+}
+
+pub async fn clear(db: Arc<Client>, key: &str) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.del(key).await?;
+	Ok(())
+}
+
+/// Rough token count: one per CJK character, one per four other characters.
+/// Good enough for budgeting without shipping a tokenizer per model.
+pub fn estimate_tokens(s: &str) -> usize {
+	let mut wide = 0;
+	let mut narrow: usize = 0;
+	for c in s.chars() {
+		if c as u32 >= 0x2E80 {
+			wide += 1;
+		} else {
+			narrow += 1;
+		}
+	}
+	wide + narrow.div_ceil(4)
+}
+
+/// The model's `context_budget` entry, else its catalogued context length, else `context_tokens`.
+pub fn budget(model: &str) -> usize {
+	AI_CONTEXT_BUDGET.read().unwrap().get(model).copied()
+		.or_else(|| super::models::get(model).and_then(|m| m.context))
+		.unwrap_or_else(|| *AI_CONTEXT_TOKENS.read().unwrap())
+}
+
+/// The newest turns that fit in `budget` tokens next to `system`, oldest first.
+/// The newest turn is always kept, even if it alone exceeds the budget.
+pub fn window(system: &str, turns: &[Turn], budget: usize) -> Vec<ChatMessage> {
+	let mut left = budget.saturating_sub(estimate_tokens(system));
+	let mut kept = Vec::new();
+	for (i, t) in turns.iter().enumerate().rev() {
+		let cost = estimate_tokens(&t.content) + 4;
+		if cost > left && i + 1 != turns.len() {
+			break;
+		}
+		left = left.saturating_sub(cost);
+		kept.push(ChatMessage { role: t.role.clone(), content: t.content.clone(), ..Default::default() });
+	}
+	kept.reverse();
+	kept
+}
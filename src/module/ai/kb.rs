@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use once_cell::sync::{Lazy, OnceCell};
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use log::info;
+
+use crate::allow;
+use crate::config::{self, Config};
+use crate::dto::Data;
+use crate::handler::DynErr;
+use crate::plugin::{Identity, MessageContext, Plugin};
+
+/// BM25 parameters, the usual defaults.
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+static CONFIG: OnceCell<config::Kb> = OnceCell::new();
+
+pub fn init(config: &config::Kb) {
+	let _ = CONFIG.set(config.clone());
+}
+
+fn config() -> config::Kb {
+	CONFIG.get().cloned().unwrap_or_default()
+}
+
+/// One document of a group's knowledge base, stored in the `ai:{gid}:kb` hash under its ID.
+#[derive(Serialize, Deserialize)]
+pub struct Doc {
+	pub title: String,
+	pub text: String,
+	pub added_by: u64,
+	pub added_at: u64,
+}
+
+/// A passage of a document.
+#[derive(Clone)]
+pub struct Passage {
+	pub doc: u64,
+	pub title: String,
+	pub text: String,
+}
+
+/// An in-memory BM25 index over a group's passages, rebuilt after every change.
+#[derive(Default)]
+struct Index {
+	passages: Vec<Passage>,
+	/// Term to (passage, term frequency).
+	postings: HashMap<String, Vec<(usize, u32)>>,
+	lengths: Vec<usize>,
+	avg_len: f64,
+}
+
+static INDEXES: Lazy<StdMutex<HashMap<u64, Arc<Index>>>> = Lazy::new(|| StdMutex::new(HashMap::new()));
+
+fn is_cjk(c: char) -> bool {
+	matches!(c, '\u{3040}'..='\u{30ff}' | '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}' | '\u{ac00}'..='\u{d7af}' | '\u{f900}'..='\u{faff}')
+}
+
+/// Lowercased words for alphabetic scripts; character bigrams for CJK, which has no spaces.
+fn tokenize(text: &str) -> Vec<String> {
+	fn flush_cjk(run: &mut Vec<char>, out: &mut Vec<String>) {
+		if run.len() == 1 {
+			out.push(run[0].to_string());
+		}
+		out.extend(run.windows(2).map(|w| w.iter().collect()));
+		run.clear();
+	}
+	let mut out = Vec::new();
+	let mut word = String::new();
+	let mut run = Vec::new();
+	for c in text.chars() {
+		if is_cjk(c) {
+			if !word.is_empty() {
+				out.push(std::mem::take(&mut word));
+			}
+			run.push(c);
+		} else {
+			flush_cjk(&mut run, &mut out);
+			if c.is_alphanumeric() {
+				word.extend(c.to_lowercase());
+			} else if !word.is_empty() {
+				out.push(std::mem::take(&mut word));
+			}
+		}
+	}
+	flush_cjk(&mut run, &mut out);
+	if !word.is_empty() {
+		out.push(word);
+	}
+	out
+}
+
+/// Splits `text` into passages of about `size` characters, keeping paragraphs together where they fit.
+fn split(text: &str, size: usize) -> Vec<String> {
+	let size = size.max(50);
+	let mut passages = Vec::new();
+	let mut cur = String::new();
+	for para in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+		if !cur.is_empty() && cur.chars().count() + para.chars().count() > size {
+			passages.push(std::mem::take(&mut cur));
+		}
+		let chars: Vec<char> = para.chars().collect();
+		for piece in chars.chunks(size) {
+			if !cur.is_empty() {
+				if cur.chars().count() + piece.len() > size {
+					passages.push(std::mem::take(&mut cur));
+				} else {
+					cur.push('\n');
+				}
+			}
+			cur.extend(piece);
+		}
+	}
+	if !cur.is_empty() {
+		passages.push(cur);
+	}
+	passages
+}
+
+impl Index {
+	fn build(docs: &[(u64, Doc)], size: usize) -> Index {
+		let mut index = Index::default();
+		for (id, doc) in docs {
+			for text in split(&doc.text, size) {
+				let n = index.passages.len();
+				// The title counts towards every passage of its document.
+				let terms = tokenize(&format!("{}\n{}", doc.title, text));
+				let mut tf: HashMap<String, u32> = HashMap::new();
+				for t in &terms {
+					*tf.entry(t.clone()).or_default() += 1;
+				}
+				for (t, f) in tf {
+					index.postings.entry(t).or_default().push((n, f));
+				}
+				index.lengths.push(terms.len());
+				index.passages.push(Passage { doc: *id, title: doc.title.clone(), text });
+			}
+		}
+		let total: usize = index.lengths.iter().sum();
+		index.avg_len = total as f64 / index.lengths.len().max(1) as f64;
+		index
+	}
+
+	/// The `k` best passages for `query` with their scores, best first.
+	fn search(&self, query: &str, k: usize) -> Vec<(f64, Passage)> {
+		let mut terms = tokenize(query);
+		terms.sort();
+		terms.dedup();
+		let n = self.passages.len() as f64;
+		let mut scores: HashMap<usize, f64> = HashMap::new();
+		for t in &terms {
+			let Some(postings) = self.postings.get(t) else {
+				continue;
+			};
+			let df = postings.len() as f64;
+			let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+			for &(p, f) in postings {
+				let f = f as f64;
+				let norm = 1.0 - B + B * self.lengths[p] as f64 / self.avg_len.max(1.0);
+				*scores.entry(p).or_default() += idf * f * (K1 + 1.0) / (f + K1 * norm);
+			}
+		}
+		let mut ranked: Vec<(usize, f64)> = scores.into_iter().collect();
+		ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+		ranked.into_iter().take(k).map(|(p, s)| (s, self.passages[p].clone())).collect()
+	}
+}
+
+fn key(gid: u64) -> String {
+	format!("ai:{}:kb", gid)
+}
+
+/// The documents of `gid`, by ID.
+pub async fn docs(gid: u64, db: Arc<Client>) -> Result<Vec<(u64, Doc)>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let raw: HashMap<String, String> = conn.hgetall(key(gid)).await?;
+	let mut docs: Vec<(u64, Doc)> = raw.into_iter()
+		.filter_map(|(id, v)| Some((id.parse().ok()?, serde_json::from_str(&v).ok()?)))
+		.collect();
+	docs.sort_by_key(|(id, _)| *id);
+	Ok(docs)
+}
+
+pub async fn add(gid: u64, db: Arc<Client>, title: &str, text: &str, user: u64) -> Result<u64, DynErr> {
+	let doc = Doc {
+		title: title.to_string(),
+		text: text.to_string(),
+		added_by: user,
+		added_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+	};
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let id: u64 = conn.incr(format!("ai:{}:kb:next", gid), 1).await?;
+	let _: () = conn.hset(key(gid), id, serde_json::to_string(&doc)?).await?;
+	INDEXES.lock().unwrap().remove(&gid);
+	Ok(id)
+}
+
+/// Whether document `id` existed.
+pub async fn remove(gid: u64, db: Arc<Client>, id: u64) -> Result<bool, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let removed: u64 = conn.hdel(key(gid), id).await?;
+	INDEXES.lock().unwrap().remove(&gid);
+	Ok(removed > 0)
+}
+
+/// The `k` passages of `gid`'s knowledge base that best match `query`, with their scores.
+pub async fn search(gid: u64, db: Arc<Client>, query: &str, k: usize) -> Result<Vec<(f64, Passage)>, DynErr> {
+	let cached = INDEXES.lock().unwrap().get(&gid).cloned();
+	let index = match cached {
+		Some(index) => index,
+		None => {
+			let index = Arc::new(Index::build(&docs(gid, db).await?, config().passage_chars));
+			INDEXES.lock().unwrap().insert(gid, index.clone());
+			index
+		}
+	};
+	Ok(index.search(query, k))
+}
+
+/// The passages to put in the prompt for `query`, as configured.
+pub async fn relevant(gid: u64, db: Arc<Client>, query: &str) -> Result<Vec<Passage>, DynErr> {
+	let k = config().top_k;
+	if k == 0 {
+		return Ok(vec![]);
+	}
+	Ok(search(gid, db, query, k).await?.into_iter().map(|(_, p)| p).collect())
+}
+
+/// `system` with the retrieved passages appended.
+pub fn with_passages(system: String, passages: &[Passage]) -> String {
+	if passages.is_empty() {
+		return system;
+	}
+	let mut out = system + "\n以下是本群资料库中可能相关的内容。回答时优先依据这些资料；与问题无关时忽略：";
+	for p in passages {
+		out += &format!("\n【{}】{}", p.title, p.text);
+	}
+	out
+}
+
+async fn add_command(ctx: &MessageContext, args: &[&str]) -> Result<String, DynErr> {
+	let gid = ctx.group_id.unwrap_or_default();
+	let (title, text) = match (super::share::replied_text(ctx, config().max_file_kb * 1024).await?, args) {
+		(Some((name, text)), []) => (name, text),
+		(Some((_, text)), title) => (title.join(" "), text),
+		(None, [title, text @ ..]) if !text.is_empty() => (title.to_string(), text.join(" ")),
+		_ => return Ok("Usage: ~kb add <title> <text>, or reply to a text file with ~kb add [title]".to_string()),
+	};
+	if text.trim().is_empty() {
+		return Ok(format!("{} is empty", title));
+	}
+	let id = add(gid, ctx.db.clone(), &title, &text, ctx.user_id).await?;
+	info!("[{} {gid}] =>kb] added {} {} ({} chars)", ctx.msg_id, id, title, text.chars().count());
+	Ok(format!("Added #{} {} ({} chars)", id, title, text.chars().count()))
+}
+
+/// `~kb add/list/remove/search`: the group's own documents the AI answers from.
+pub struct KbPlugin;
+
+#[async_trait]
+impl Plugin for KbPlugin {
+	fn name(&self) -> &'static str {
+		"kb"
+	}
+
+	fn commands(&self) -> Vec<&'static str> {
+		vec!["kb"]
+	}
+
+	async fn on_load(&self, config: &Config, _db: Arc<Client>) -> Result<(), DynErr> {
+		init(&config.ai.kb);
+		Ok(())
+	}
+
+	async fn on_command(&self, ctx: &MessageContext, cmd: &str, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+		if cmd != "kb" {
+			return Ok(None);
+		}
+		let gid = ctx.group_id.unwrap_or_default();
+		let ret = match args {
+			["add", rest @ ..] => {
+				allow!(&ctx.sender, Identity::Admin); // Require admin to add documents
+				add_command(ctx, rest).await?
+			}
+			["list"] => {
+				let docs = docs(gid, ctx.db.clone()).await?;
+				if docs.is_empty() {
+					"The knowledge base is empty".to_string()
+				} else {
+					docs.iter()
+						.map(|(id, d)| format!("#{} {} ({} chars)", id, d.title, d.text.chars().count()))
+						.collect::<Vec<String>>()
+						.join("\n")
+				}
+			}
+			["remove", id] => {
+				allow!(&ctx.sender, Identity::Admin); // Require admin to remove documents
+				let Ok(id) = id.trim_start_matches('#').parse() else {
+					return Ok(Some(vec![Data::string("Usage: ~kb remove <id>".to_string())]));
+				};
+				if remove(gid, ctx.db.clone(), id).await? {
+					format!("Removed #{}", id)
+				} else {
+					format!("No document #{}", id)
+				}
+			}
+			["search", query @ ..] if !query.is_empty() => {
+				let hits = search(gid, ctx.db.clone(), &query.join(" "), 5).await?;
+				if hits.is_empty() {
+					"Nothing found".to_string()
+				} else {
+					hits.iter()
+						.map(|(score, p)| format!("#{} {} ({:.2})\n{}", p.doc, p.title, score, p.text))
+						.collect::<Vec<String>>()
+						.join("\n\n")
+				}
+			}
+			_ => "Usage: ~kb [add <title> <text> | list | remove <id> | search <query>]".to_string(),
+		};
+		Ok(Some(vec![Data::string(ret)]))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn doc(title: &str, text: &str) -> Doc {
+		Doc { title: title.to_string(), text: text.to_string(), added_by: 0, added_at: 0 }
+	}
+
+	#[test]
+	fn words_are_lowercased() {
+		assert_eq!(tokenize("Hello, World! v2"), ["hello", "world", "v2"]);
+	}
+
+	#[test]
+	fn cjk_becomes_bigrams() {
+		assert_eq!(tokenize("机器人"), ["机器", "器人"]);
+		assert_eq!(tokenize("猫"), ["猫"]);
+		assert_eq!(tokenize("QQ机器人bot"), ["qq", "机器", "器人", "bot"]);
+	}
+
+	#[test]
+	fn short_paragraphs_share_a_passage() {
+		assert_eq!(split("one\n\ntwo\n", 100), ["one\ntwo"]);
+	}
+
+	#[test]
+	fn long_paragraphs_are_cut() {
+		let text = "a".repeat(120);
+		let passages = split(&text, 50);
+		assert_eq!(passages.iter().map(|p| p.chars().count()).collect::<Vec<_>>(), [50, 50, 20]);
+		// A paragraph that doesn't fit starts a new passage.
+		let text = format!("{}\n{}", "b".repeat(40), "c".repeat(40));
+		assert_eq!(split(&text, 50), ["b".repeat(40), "c".repeat(40)]);
+	}
+
+	#[test]
+	fn search_ranks_matching_passages() {
+		let index = Index::build(&[
+			(1, doc("Rules", "No spam in the group. Be nice.")),
+			(2, doc("签到", "每天发送签到可以获得积分")),
+			(3, doc("Games", "Type ~roll to roll a dice.")),
+		], 500);
+		let hits = index.search("how do I roll dice", 2);
+		assert_eq!(hits[0].1.doc, 3);
+		assert!(hits.iter().all(|(score, _)| *score > 0.0));
+		assert_eq!(index.search("怎么签到", 1)[0].1.doc, 2);
+		assert!(index.search("nothing matches", 3).is_empty());
+		assert_eq!(index.search("the group rules", 1).len(), 1);
+	}
+}
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+
+use redis::{AsyncCommands, Client};
+use log::info;
+
+use super::backend::{AiBackend, ChatMessage, ChatRequest};
+use super::history::{self, Turn};
+use super::persona::Persona;
+use crate::constants::{AI_SUMMARIZE_TOKENS, AI_SUMMARIZE_TURNS};
+use crate::handler::DynErr;
+
+const SUMMARY_PROMPT: &str = "请总结以上对话，供你在新对话中继续使用：列出参与者、讨论过的话题、已经得出的结论、尚未解决的问题以及用户表达过的偏好。只输出总结本身，不超过300字。";
+
+pub fn key(conv: &str, bot: &str) -> String {
+	format!("ai:{}:{}:memory", conv, bot)
+}
+
+/// The summary carried over from earlier conversations, if any.
+pub async fn get(db: Arc<Client>, conv: &str, bot: &str) -> Result<Option<String>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	Ok(conn.get(key(conv, bot)).await?)
+}
+
+pub async fn clear(db: Arc<Client>, conv: &str, bot: &str) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.del(key(conv, bot)).await?;
+	Ok(())
+}
+
+/// `system` with the carried-over summary appended.
+pub fn with_memory(system: String, memory: Option<&str>) -> String {
+	match memory {
+		Some(m) if !m.is_empty() => format!("{}\n此前对话的摘要：{}", system, m),
+		_ => system,
+	}
+}
+
+/// Whether `turns` has grown past either configured threshold.
+pub fn due(turns: &[Turn]) -> bool {
+	let max_turns = *AI_SUMMARIZE_TURNS.read().unwrap();
+	let max_tokens = *AI_SUMMARIZE_TOKENS.read().unwrap();
+	if max_turns > 0 && turns.len() >= max_turns {
+		return true;
+	}
+	max_tokens > 0 && turns.iter().map(|t| history::estimate_tokens(&t.content)).sum::<usize>() >= max_tokens
+}
+
+/// Asks the model to summarise the conversation, then starts a fresh one that
+/// carries the summary in its system prompt. Call with the conversation lock held.
+pub async fn summarize(backend: &dyn AiBackend, conv: &str, model: &str, persona: &Persona, turns: &[Turn], db: Arc<Client>) -> Result<String, DynErr> {
+	let bot = super::bot_uid(model);
+	let system = with_memory(persona.system(), get(db.clone(), conv, &bot).await?.as_deref());
+
+	let mut messages = history::window(&system, turns, history::budget(model));
+	messages.push(ChatMessage::user(SUMMARY_PROMPT));
+	let req = ChatRequest {
+		conv: conv.to_string(),
+		user: 0,
+		model: model.to_string(),
+		system,
+		temperature: Some(0.3),
+		messages,
+		db: db.clone(),
+	};
+	let summary = backend.chat(&req).await?;
+
+	super::clear_record(conv, db.clone(), &bot).await?;
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set(key(conv, &bot), &summary).await?;
+	info!("[{} {}] conversation summarised after {} turn(s)", conv, bot, turns.len());
+	Ok(summary)
+}
@@ -0,0 +1,808 @@
+pub mod backend;
+pub mod history;
+pub mod persona;
+pub mod stream;
+pub mod queue;
+pub mod memory;
+pub mod tools;
+pub mod thread;
+pub mod models;
+pub mod usage;
+pub mod moderation;
+pub mod gate;
+pub mod retry;
+pub mod kb;
+pub mod recent;
+pub mod share;
+pub mod template;
+pub mod draw;
+pub mod voice;
+pub mod speech;
+pub mod transfer;
+
+use std::sync::Arc;
+use redis::{Client, AsyncCommands};
+use uuid::Uuid;
+use crate::constants::{*};
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::{info, error, warn};
+
+use crate::allow;
+use crate::config::Config;
+use crate::handler::DynErr;
+use crate::plugin::{Identity, MessageContext, Plugin};
+use backend::{AiBackend, ChatRequest};
+use history::Turn;
+use persona::Persona;
+
+use crate::dto::{*};
+
+/// Conversation state is keyed by the model's `bot_uid` from the catalogue.
+pub fn bot_uid(model: &str) -> String {
+	models::bot_uid(model)
+}
+
+/// The backend, persona and model conversations in `gid` currently use.
+/// The model is the group's `~ai !model` choice, else the persona's, else the backend default.
+/// A catalogued model is served by its own backend, whatever `~ai !backend` says.
+pub async fn setup(gid: u64, db: Arc<Client>) -> Result<(Arc<dyn AiBackend>, Persona, String), DynErr> {
+	let mut backend = backend::for_group(gid, db.clone()).await?;
+	let persona = persona::active(gid, db.clone()).await?;
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let model: Option<String> = conn.get(format!("ai:{}:model",gid)).await?;
+	let model = model.or(persona.model.clone()).unwrap_or_else(|| backend.default_model());
+	if let Some(info) = models::get(&model).filter(|m| m.backend != backend.name()) {
+		backend = backend::get(&info.backend).ok_or_else(|| format!("AI backend {} is not configured", info.backend))?;
+	}
+	Ok((Arc::new(usage::Metered(Arc::new(retry::Resilient(backend)))), persona, model))
+}
+
+/// Starts conversation `conv` of bot `b` over. `main` means the group's current model.
+pub async fn clear_record(conv: &str, db:Arc<Client>, b: &str) -> Result<(), crate::handler::DynErr> {
+	let bot;
+
+	if b == "main" {
+		let (_, _, main_model) = setup(thread::group_of(conv), db.clone()).await?;
+		bot = bot_uid(&main_model);
+	}else{
+		bot = b.to_string();
+	}
+	history::clear(db.clone(), &history::key(conv, &bot)).await?;
+	memory::clear(db.clone(), conv, &bot).await?;
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set(format!("ai:{}:{}:conv", conv, bot), Uuid::new_v4().to_string()).await?;
+	let _: () = conn.set(format!("ai:{}:{}:prev", conv, bot), Uuid::new_v4().to_string()).await?;
+	let _: () = conn.set(format!("ai:{}:{}:now", conv, bot), Uuid::new_v4().to_string()).await?;
+	let _: () = conn.set(format!("ai:{}:{}:count", conv, bot), 0).await?;
+
+	Ok(())
+}
+
+/// Deletes the state of throwaway conversation `conv` of bot `bot`.
+pub async fn drop_record(conv: &str, db: Arc<Client>, bot: &str) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let keys: Vec<String> = ["conv", "prev", "now", "count"].iter().map(|k| format!("ai:{}:{}:{}", conv, bot, k)).collect();
+	let _: () = conn.del(keys).await?;
+	Ok(())
+}
+
+pub async fn set_model(gid: u64, conv: &str, db:Arc<Client>, model: &str) -> Result<Vec<Data>, crate::handler::DynErr> {
+	let Some(info) = models::get(model) else {
+		let names: Vec<&str> = models::all().iter().map(|m| m.name.as_str()).collect();
+		return Ok(vec![Data::string(format!("Unknown model {}, available: {}", model, names.join(", ")))]);
+	};
+	if backend::get(&info.backend).is_none() {
+		return Ok(vec![Data::string(format!("Model {} needs backend {}, which is not configured", model, info.backend))]);
+	}
+
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set(format!("ai:{}:model", gid), model.to_string()).await?;
+
+	clear_record(conv, db, "main").await?;
+	Ok(vec![Data::string(format!("Model set to {}", model))])
+}
+
+pub async fn check_join(gid: u64, db:Arc<Client>) -> Result<bool, crate::handler::DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	Ok(conn.exists(format!("ai:{}:JOIN", gid)).await?)
+}
+
+pub async fn set_join(gid: u64, db:Arc<Client>) -> Result<(), crate::handler::DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let key = format!("ai:{}:JOIN", gid);
+	let t = *AI_ENGAGE_TIME.read().unwrap();
+	if conn.exists(&key).await? {
+		let _:() = conn.expire(key, t).await?;
+	}else{
+		let _:() = conn.set(&key, 1).await?;
+		let _:() = conn.expire(key, t).await?;
+	}
+	Ok(())
+}
+
+
+/// Sends `msg` from `ctx`'s sender to the AI and records the exchange in the local history.
+pub async fn main_conversation(ctx: &MessageContext, msg: &str) -> Result<Vec<Data>, crate::handler::DynErr>{
+	converse(ctx, msg, vec![], false).await
+}
+
+/// Like `main_conversation`, with `lead` (e.g. an @ and a quote) in front of the first
+/// message sent. When streaming, that is the first piece rather than the returned reply,
+/// and the returned reply holds only what wasn't sent yet.
+///
+/// `unprompted` turns answer a message nobody addressed to the bot: they are never
+/// streamed, and the model may decline with `gate::NO_REPLY`.
+pub async fn converse(ctx: &MessageContext, msg: &str, mut lead: Vec<Data>, unprompted: bool) -> Result<Vec<Data>, DynErr> {
+	let gid = ctx.group_id.unwrap_or_default();
+	let db = ctx.db.clone();
+
+	// Refusals go to whoever asked; someone who never addressed the bot hears nothing.
+	if let Some(which) = usage::over_quota(gid, ctx.user_id, db.clone()).await? {
+		if unprompted {
+			return Ok(vec![]);
+		}
+		lead.push(Data::string(usage::refusal(ctx, which).await?));
+		return Ok(lead);
+	}
+	let msg = match moderation::check(ctx, moderation::Kind::Prompt, msg).await? {
+		moderation::Verdict::Allow(msg) => msg,
+		moderation::Verdict::Refuse(_) if unprompted => return Ok(vec![]),
+		moderation::Verdict::Refuse(refusal) => {
+			lead.push(Data::string(refusal));
+			return Ok(lead);
+		}
+	};
+	let conv = thread::conversation(ctx).await?;
+	let (backend, persona, main_model) = setup(gid, db.clone()).await?;
+	let bot = bot_uid(&main_model);
+	let ticket = queue::acquire(ctx, &format!("{}:{}", conv, bot)).await?;
+	let key = history::key(&conv, &bot);
+
+	let prompt = template::render(&persona.system(), &template::vars(ctx).await);
+	let mut system = memory::with_memory(prompt, memory::get(db.clone(), &conv, &bot).await?.as_deref());
+	system = kb::with_passages(system, &kb::relevant(gid, db.clone(), &msg).await?);
+	if ctx.at_self && !unprompted {
+		system = recent::with_transcript(system, ctx).await?;
+	}
+	if unprompted {
+		system += &gate::hint();
+	}
+	let question = Turn::new("user", &ctx.nickname, &msg);
+	let mut turns = history::load(db.clone(), &key).await?;
+	turns.push(question.clone());
+
+	let req = ChatRequest {
+		conv: conv.clone(),
+		user: ctx.user_id,
+		messages: history::window(&system, &turns, history::budget(&main_model)),
+		model: main_model,
+		system,
+		temperature: persona.temperature,
+		db: db.clone(),
+	};
+	let tools_on = tools::enabled(gid, db.clone()).await?;
+	let voice = speech::voice_for(gid, db.clone(), &persona).await?;
+	let (main_resp, rest) = if !tools_on && !unprompted && voice.is_none() && ctx.outbox.is_some() && stream::enabled(gid, db.clone()).await? {
+		stream_reply(ctx, backend.as_ref(), &req, &mut lead).await?
+	} else {
+		let raw = if tools_on {
+			tools::chat(ctx, backend.as_ref(), &req).await?
+		} else {
+			backend.chat(&req).await?
+		};
+		if unprompted && gate::is_no_reply(&raw) {
+			info!("[{} <=ai_reply] (no reply)", ctx.msg_id);
+			history::push(db.clone(), &key, &[question]).await?;
+			return Ok(vec![]);
+		}
+		let resp = moderated(ctx, &raw).await?;
+		(resp.clone(), resp)
+	};
+	info!("[{} <=ai_reply] {}", ctx.msg_id, main_resp);
+	if unprompted {
+		gate::replied(gid, db.clone()).await?;
+	}
+
+	let answer = Turn::new("assistant", &persona.display_name, &main_resp);
+	history::push(db.clone(), &key, &[question, answer.clone()]).await?;
+
+	turns.push(answer);
+	if memory::due(&turns) {
+		// Summarise after replying; the ticket keeps the conversation locked until it's done.
+		let model = req.model.clone();
+		tokio::spawn(async move {
+			let _ticket = ticket;
+			if let Err(e) = memory::summarize(backend.as_ref(), &conv, &model, &persona, &turns, db).await {
+				error!("[{} {}] summarise failed: {:?}", conv, model, e);
+			}
+		});
+	}
+
+	if rest.is_empty() && lead.is_empty() {
+		return Ok(vec![]);
+	}
+	let spoken = match &voice {
+		Some(v) => speech::speak(&rest, v).await,
+		None => None,
+	};
+	let message = match spoken {
+		// A voice message goes alone, without the @ and the quote.
+		Some(record) => vec![record],
+		None => {
+			lead.push(Data::string(rest));
+			lead
+		}
+	};
+	if thread::is_thread(&req.conv) && ctx.outbox.is_some() {
+		// Sent here rather than returned, to learn the message ID replies will point at.
+		say(ctx, &req.conv, message).await?;
+		return Ok(vec![]);
+	}
+	Ok(message)
+}
+
+/// Sends `message` right away. In a thread, replies to it will continue `conv`.
+async fn say(ctx: &MessageContext, conv: &str, message: Vec<Data>) -> Result<(), DynErr> {
+	if !thread::is_thread(conv) {
+		return ctx.send(message).await;
+	}
+	let id = ctx.send_for_id(message).await?;
+	thread::remember(conv, id, ctx.db.clone()).await
+}
+
+/// `reply` as it may be shown: masked, or replaced by a refusal.
+async fn moderated(ctx: &MessageContext, reply: &str) -> Result<String, DynErr> {
+	Ok(match moderation::check(ctx, moderation::Kind::Reply, reply).await? {
+		moderation::Verdict::Allow(text) | moderation::Verdict::Refuse(text) => text,
+	})
+}
+
+/// Sends the reply piece by piece as the backend produces it. Moderation sees the whole
+/// reply so far, so a blocked word split across two pieces is still caught.
+/// A refusal ends the reply.
+/// Returns the reply as shown and the tail that is still unsent.
+async fn stream_reply(ctx: &MessageContext, backend: &dyn AiBackend, req: &ChatRequest, lead: &mut Vec<Data>) -> Result<(String, String), DynErr> {
+	let mut stream = backend.chat_stream(req).await?;
+	let mut chunker = stream::Chunker::default();
+	let mut raw = String::new();
+	// The moderated reply as of the last piece sent.
+	let mut checked = String::new();
+	let mut shown = String::new();
+	while let Some(chunk) = stream.next().await {
+		let chunk = chunk?;
+		let Some(piece) = chunker.push(&chunk) else {
+			continue;
+		};
+		raw += &piece;
+		let piece = match moderation::check(ctx, moderation::Kind::Reply, &raw).await? {
+			moderation::Verdict::Allow(now) => stream::unsent(&std::mem::replace(&mut checked, now.clone()), &now),
+			moderation::Verdict::Refuse(refusal) => {
+				shown += &refusal;
+				return Ok((shown, refusal));
+			}
+		};
+		if piece.is_empty() {
+			continue;
+		}
+		shown += &piece;
+		let mut message = std::mem::take(lead);
+		message.push(Data::string(piece));
+		if let Err(e) = say(ctx, &req.conv, message).await {
+			error!("[{} <=ai_stream] {:?}", ctx.msg_id, e);
+		}
+	}
+	raw += &chunker.finish();
+	let rest = match moderation::check(ctx, moderation::Kind::Reply, &raw).await? {
+		moderation::Verdict::Allow(now) => stream::unsent(&checked, &now).trim().to_string(),
+		moderation::Verdict::Refuse(refusal) => refusal,
+	};
+	shown += &rest;
+	Ok((shown, rest))
+}
+
+/// `~ai !history [n]` shows the newest `n` turns; `~ai !history pop [n]` deletes them.
+async fn history_command(ctx: &MessageContext, args: &[&str]) -> Result<Vec<Data>, DynErr> {
+	let gid = ctx.group_id.unwrap_or_default();
+	let (_, _, model) = setup(gid, ctx.db.clone()).await?;
+	let key = history::key(&thread::conversation(ctx).await?, &bot_uid(&model));
+
+	if args.first() == Some(&"pop") {
+		let n = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(2);
+		history::pop(ctx.db.clone(), &key, n).await?;
+		return Ok(vec![Data::string(format!("Removed {} turn(s)", n))]);
+	}
+
+	let n = args.first().and_then(|n| n.parse().ok()).unwrap_or(6);
+	let turns = history::load(ctx.db.clone(), &key).await?;
+	if turns.is_empty() {
+		return Ok(vec![Data::string("History is empty".to_string())]);
+	}
+	let mut out = format!("{} turn(s), showing the last {}:", turns.len(), n.min(turns.len()));
+	for t in turns.iter().skip(turns.len().saturating_sub(n)) {
+		let who = if t.role == "assistant" { "AI" } else { t.speaker.as_str() };
+		let text: String = t.content.chars().take(100).collect();
+		out += &format!("\n[{}] {}", who, text);
+	}
+	Ok(vec![Data::string(out)])
+}
+
+/// `~ai !persona [name | list | show [name] | set <name> <field> <value> | del <name>]`
+async fn persona_command(ctx: &MessageContext, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+	let gid = ctx.group_id.unwrap_or_default();
+	let db = ctx.db.clone();
+	let ret = match args {
+		[] | ["show"] => persona::active(gid, db).await?.describe(),
+		["show", name] => match persona::get(db, name).await? {
+			Some(p) => p.describe(),
+			None => format!("No persona named {}", name),
+		},
+		["list"] => {
+			let current = persona::active(gid, db.clone()).await?.name;
+			format!("Current: {}\nAvailable: default, {}", current, persona::list(db).await?.join(", "))
+		}
+		["set", name, field, value @ ..] if !value.is_empty() => {
+			allow!(&ctx.sender, Identity::Owner); // Require owner to edit personas, which every group shares
+			match persona::set_field(db, name, field, &value.join(" ")).await? {
+				Ok(()) => format!("Persona {} updated", name),
+				Err(problem) => problem,
+			}
+		}
+		["del", name] => {
+			allow!(&ctx.sender, Identity::Owner); // Require owner to delete personas
+			persona::delete(db, name).await?;
+			format!("Persona {} deleted", name)
+		}
+		[name] => {
+			allow!(&ctx.sender, Identity::Admin); // Require admin to switch the group's persona
+			match persona::activate(gid, db.clone(), name).await? {
+				Ok(()) => {
+					clear_record(&thread::conversation(ctx).await?, db, "main").await?;
+					format!("Switched to persona {}", name)
+				}
+				Err(problem) => problem,
+			}
+		}
+		_ => format!("Usage: ~ai !persona [name | list | show [name] | set <name> <{}> <value> | del <name>]", persona::FIELDS.join("|")),
+	};
+	Ok(Some(vec![Data::string(ret)]))
+}
+
+/// `~ai !usage` shows today's usage of the sender and this group; `~ai !usage all [YYYYMMDD]` of everyone.
+async fn usage_command(ctx: &MessageContext, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+	let gid = ctx.group_id.unwrap_or_default();
+	let fmt_quota = |q: u64| if q == 0 { "unlimited".to_string() } else { q.to_string() };
+	let ret = match args {
+		[] => {
+			let counters = usage::day(ctx.db.clone(), &usage::today()).await?;
+			let mut out = format!("Today you used {} / {} tokens",
+				usage::tokens(&counters, &format!("user:{}", ctx.user_id)), fmt_quota(*AI_QUOTA_USER_DAILY.read().unwrap()));
+			if gid != 0 {
+				out += &format!("\nThis group used {} / {} tokens",
+					usage::tokens(&counters, &format!("group:{}", gid)), fmt_quota(*AI_QUOTA_GROUP_DAILY.read().unwrap()));
+			}
+			if draw::enabled() {
+				out += &format!("\nYou drew {} image(s)", counters.get(&format!("user:{}:img", ctx.user_id)).copied().unwrap_or_default());
+			}
+			out
+		}
+		["all", rest @ ..] => {
+			allow!(&ctx.sender, Identity::Owner); // Require owner for everyone's usage
+			let day = rest.first().map(|d| d.to_string()).unwrap_or_else(usage::today);
+			let counters = usage::day(ctx.db.clone(), &day).await?;
+			let mut out = format!("Usage on {} (estimated tokens in+out, requests)", day);
+			for (title, dim) in [("Groups", "group"), ("Users", "user"), ("Models", "model")] {
+				let mut ids: Vec<&str> = counters.keys()
+					.filter_map(|k| k.strip_prefix(dim)?.strip_prefix(':')?.strip_suffix(":req"))
+					.collect();
+				ids.sort_by_key(|id| std::cmp::Reverse(usage::tokens(&counters, &format!("{}:{}", dim, id))));
+				out += &format!("\n{}:", title);
+				for id in ids.iter().take(10) {
+					let full = format!("{}:{}", dim, id);
+					out += &format!("\n  {} {} ({})", id, usage::tokens(&counters, &full),
+						counters.get(&format!("{}:req", full)).copied().unwrap_or_default());
+				}
+			}
+			out
+		}
+		_ => "Usage: ~ai !usage [all [YYYYMMDD]]".to_string(),
+	};
+	Ok(Some(vec![Data::string(ret)]))
+}
+
+/// `~ai !moderation [n]` shows the newest blocked or masked texts.
+async fn moderation_command(ctx: &MessageContext, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+	allow!(&ctx.sender, Identity::Owner); // Require owner to review moderation hits
+	let n = args.first().and_then(|n| n.parse().ok()).unwrap_or(10);
+	let hits = moderation::recent(ctx.db.clone(), n).await?;
+	if hits.is_empty() {
+		return Ok(Some(vec![Data::string("Nothing blocked yet".to_string())]));
+	}
+	let lines: Vec<String> = hits.iter()
+		.map(|h| format!("[{} {}] {} {}: {}", h["gid"], h["user"], h["kind"].as_str().unwrap_or_default(),
+			h["reason"].as_str().unwrap_or_default(), h["text"].as_str().unwrap_or_default()))
+		.collect();
+	Ok(Some(vec![Data::string(lines.join("\n"))]))
+}
+
+/// `~ai !tools [on | off | log [n]]`
+async fn tools_command(ctx: &MessageContext, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+	let gid = ctx.group_id.unwrap_or_default();
+	let ret = match args {
+		[] => {
+			let on = tools::enabled(gid, ctx.db.clone()).await?;
+			format!("Tools are {}\nAvailable: {}", if on { "on" } else { "off" }, tools::names().join(", "))
+		}
+		[state @ ("on" | "off")] => {
+			allow!(&ctx.sender, Identity::Admin); // Require admin to toggle tools
+			tools::set_enabled(gid, ctx.db.clone(), *state == "on").await?;
+			format!("Tools turned {}", state)
+		}
+		["log", rest @ ..] => {
+			allow!(&ctx.sender, Identity::Owner); // Require owner to read the call log
+			let n = rest.first().and_then(|n| n.parse().ok()).unwrap_or(10);
+			let calls = tools::recent(ctx.db.clone(), n).await?;
+			if calls.is_empty() {
+				"No tool calls yet".to_string()
+			} else {
+				calls.iter()
+					.map(|c| format!("[{} {}] {}({}) {} {}", c["gid"], c["user"], c["tool"].as_str().unwrap_or_default(), c["args"],
+						if c["ok"] == true { "->" } else { "!!" }, c["result"].as_str().unwrap_or_default()))
+					.collect::<Vec<String>>()
+					.join("\n")
+			}
+		}
+		_ => "Usage: ~ai !tools [on | off | log [n]]".to_string(),
+	};
+	Ok(Some(vec![Data::string(ret)]))
+}
+
+/// `~ai !template [name [text | reset]]`. The text is taken as written, newlines included.
+async fn template_command(ctx: &MessageContext, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+	let gid = ctx.group_id.unwrap_or_default();
+	let db = ctx.db.clone();
+	let builtin = |name: &str| match name {
+		"message" => template::DEFAULT_MESSAGE.to_string(),
+		"quota.group" => usage::message(usage::Exceeded::Group).to_string(),
+		"quota.user" => usage::message(usage::Exceeded::User).to_string(),
+		"draw.quota" => draw::quota_message().to_string(),
+		_ => retry::message(match name {
+			"error.auth_expired" => backend::error::ErrorKind::AuthExpired,
+			"error.rate_limited" => backend::error::ErrorKind::RateLimited,
+			"error.timeout" => backend::error::ErrorKind::Timeout,
+			"error.circuit_open" => backend::error::ErrorKind::CircuitOpen,
+			_ => backend::error::ErrorKind::Other,
+		}).to_string(),
+	};
+	let ret = match args {
+		[] => {
+			let mut out = format!("Variables: {}", template::VARS.map(|v| format!("{{{}}}", v)).join(" "));
+			for name in template::NAMES {
+				let custom = template::get(gid, db.clone(), name).await?.is_some();
+				out += &format!("\n{}{}", name, if custom { " (custom)" } else { "" });
+			}
+			out
+		}
+		[name] if template::NAMES.contains(name) => {
+			let builtin = builtin(name);
+			template::get_or(gid, db, name, &builtin).await?
+		}
+		[name, "reset"] => {
+			allow!(&ctx.sender, Identity::Admin); // Require admin to change templates
+			match template::reset(gid, db, name).await? {
+				Ok(()) => format!("Template {} reset", name),
+				Err(problem) => problem,
+			}
+		}
+		[name, _, ..] => {
+			allow!(&ctx.sender, Identity::Admin); // Require admin to change templates
+			// Everything after `!template <name>`, newlines included.
+			let text = ctx.text.split_once("!template").map(|(_, t)| t.trim_start())
+				.and_then(|t| t.strip_prefix(*name))
+				.map(|t| t.trim())
+				.unwrap_or_default();
+			match template::set(gid, db, name, text).await? {
+				Ok(()) => format!("Template {} set", name),
+				Err(problem) => problem,
+			}
+		}
+		_ => format!("Usage: ~ai !template [<{}> [text | reset]]", template::NAMES.join("|")),
+	};
+	Ok(Some(vec![Data::string(ret)]))
+}
+
+/// `~ai !token [session_id]` shows or replaces the Monica session, in private only.
+async fn token_command(ctx: &MessageContext, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+	allow!(&ctx.sender, Identity::Owner); // Require owner for the session token
+	if ctx.group_id.is_some() {
+		return Ok(Some(vec![Data::string("Send ~ai !token in private, not in a group".to_string())]));
+	}
+	let session = match args {
+		[] => backend::monica::check_session().await?,
+		[token] => backend::monica::rotate_token(ctx.db.clone(), token).await?,
+		_ => return Ok(Some(vec![Data::string("Usage: ~ai !token [session_id]".to_string())])),
+	};
+	let ret = match session {
+		backend::monica::Session::Valid => "Monica session is valid",
+		backend::monica::Session::Expired => "Monica session is expired, send a new one with ~ai !token <session_id>",
+		backend::monica::Session::Unknown => "Monica session is unknown",
+	};
+	Ok(Some(vec![Data::string(ret.to_string())]))
+}
+
+/// Asks the AI about `ctx`, framed by the `message` template. `voice` is what its voice messages said.
+async fn default_handler(ctx: &MessageContext, reply: Option<u64>, voice: &str) -> Result<Vec<Data>, DynErr> {
+    let gid = ctx.group_id.unwrap_or_default();
+    let mut images = String::new();
+    for i in &ctx.images {
+        images += &format!("图片：{} {}\n", i.summary, backend::vision::describe(gid, ctx.user_id, ctx.db.clone(), i).await);
+    }
+    let mut vars = template::vars(ctx).await;
+    vars.insert("images", images);
+    vars.insert("voice", voice.to_string());
+    let prompt = template::render(&template::get_or(gid, ctx.db.clone(), "message", template::DEFAULT_MESSAGE).await?, &vars);
+    let lead = match reply {
+        Some(id) => vec![Data::at(ctx.user_id), Data::reply(id)],
+        None => vec![],
+    };
+    converse(ctx, &prompt, lead, reply.is_none()).await
+}
+
+/// The reply to a conversation turn, with failures explained in plain words.
+async fn answer(ctx: &MessageContext, r: Result<Vec<Data>, DynErr>) -> Vec<Data> {
+	match r {
+		Ok(reply) => reply,
+		Err(e) => retry::friendly(ctx, e).await,
+	}
+}
+
+pub struct AiPlugin;
+
+#[async_trait]
+impl Plugin for AiPlugin {
+	fn name(&self) -> &'static str {
+		"ai"
+	}
+
+	fn commands(&self) -> Vec<&'static str> {
+		vec!["ai"]
+	}
+
+	async fn on_load(&self, config: &Config, db: Arc<Client>) -> Result<(), DynErr> {
+		set_ai_token(config.ai.token.clone());
+		set_ai_endpoint(config.ai.endpoint.clone());
+		set_ai_default_model(config.ai.default_model.clone());
+		set_ai_init_prompt(config.ai.init_prompt.clone());
+		set_ai_engage_time(config.ai.engage_time);
+		set_ai_auto_join(config.ai.auto_join);
+		set_ai_default_backend(config.ai.backend.clone());
+		set_ai_context_tokens(config.ai.context_tokens);
+		set_ai_context_budget(config.ai.context_budget.clone());
+		set_ai_history_len(config.ai.history_len);
+		set_ai_stream(config.ai.stream, config.ai.stream_min_chars, config.ai.stream_interval_ms);
+		set_ai_max_concurrent(config.ai.max_concurrent.max(1));
+		set_ai_summarize(config.ai.summarize_turns, config.ai.summarize_tokens);
+		set_ai_tools(config.ai.tools, config.ai.max_tool_rounds, config.ai.tool_commands.clone());
+		set_ai_quota(config.ai.quota_group_daily, config.ai.quota_user_daily);
+		set_ai_recent_messages(config.ai.recent_messages);
+		models::init(&config.ai);
+		moderation::init(&config.ai.moderation);
+		gate::init(&config.ai.join);
+		retry::init(&config.ai.retry, &config.ai.locale);
+		template::init(&config.ai.templates, &config.ai.locale);
+		voice::init(&config.ai);
+		backend::init(&config.ai);
+		backend::monica::load_token(db.clone()).await?;
+		if !AI_TOKEN.read().unwrap().is_empty() {
+			tokio::spawn(async {
+				match backend::monica::check_session().await {
+					Ok(backend::monica::Session::Expired) => error!("Monica session is expired, send ~ai !token <session_id> in private"),
+					Ok(_) => info!("Monica session is valid"),
+					Err(e) => warn!("Could not check the Monica session: {:?}", e),
+				}
+			});
+		}
+		let migrated = models::migrate(db).await?;
+		if migrated > 0 {
+			info!("Moved {} group model choice(s) to ai:{{gid}}:model", migrated);
+		}
+		Ok(())
+	}
+
+	async fn on_message(&self, ctx: &MessageContext) -> Result<Option<Vec<Data>>, DynErr> {
+		// Private chats only talk to the AI through `~ai`.
+		let Some(gid) = ctx.group_id else {
+			return Ok(None);
+		};
+		// A voice message can't @ the bot, so it is only worth transcribing for
+		// auto-join or to echo it.
+		let joined = *AI_AUTO_JOIN.read().unwrap() && check_join(gid, ctx.db.clone()).await?;
+		let voice = if !ctx.records.is_empty() && voice::enabled() && (joined || voice::echoes()) {
+			voice::heard(ctx).await.unwrap_or_else(|e| {
+				warn!("[{} {gid}] transcription failed: {:?}", ctx.msg_id, e);
+				String::new()
+			})
+		} else {
+			String::new()
+		};
+		recent::record(ctx, &voice).await?;
+		if ctx.at_self {
+			set_join(gid, ctx.db.clone()).await?;
+			info!("[{} {gid} {}] >=ai_at] {}", ctx.msg_id, ctx.nickname, ctx.text);
+			Ok(Some(answer(ctx, default_handler(ctx, Some(ctx.msg_id), &voice).await).await))
+		} else if thread::continues(ctx).await? {
+			info!("[{} {gid} {}] >=ai_thread] {}", ctx.msg_id, ctx.nickname, ctx.text);
+			Ok(Some(answer(ctx, default_handler(ctx, Some(ctx.msg_id), &voice).await).await))
+		} else if joined && gate::should_reply(ctx, &format!("{}{}", ctx.text, voice)).await? {
+			info!("[{} {gid} {}] =>ai_auto] {}{}", ctx.msg_id, ctx.nickname, ctx.text, voice);
+			Ok(Some(answer(ctx, default_handler(ctx, None, &voice).await).await))
+		} else {
+			Ok(None)
+		}
+	}
+
+	async fn on_command(&self, ctx: &MessageContext, cmd: &str, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+		if cmd != "ai" {
+			return Ok(None);
+		}
+		let gid = ctx.group_id.unwrap_or_default();
+		let conv = thread::conversation(ctx).await?;
+		// Outside a reply, per-thread mode hands out a brand new thread, which has nothing to act on.
+		if matches!(args.first(), Some(&("!clear" | "!history" | "!memory" | "!export" | "!import")))
+			&& thread::is_thread(&conv) && !thread::continues(ctx).await? {
+			return Ok(Some(vec![Data::string(format!("Conversations here are per thread: reply to one of my messages in the thread with ~ai {}", args[0]))]));
+		}
+		let ret = if args.first() == Some(&"!clear") {
+			if ctx.group_id.is_some() {
+				allow!(&ctx.sender, Identity::Owner); // Require owner for clear
+			}
+			clear_record(&conv, ctx.db.clone(), "main").await?;
+			if ctx.group_id.is_none() && args.get(1) == Some(&"all") {
+				for m in models::all() {
+					clear_record(&conv, ctx.db.clone(), &m.bot_uid).await?
+				}
+			}
+			vec![Data::string("Record cleared".to_string())]
+		} else if args.first() == Some(&"!model") {
+			match args.get(1) {
+				Some(model) => {
+					allow!(&ctx.sender, Identity::Owner); // Require owner for model
+					set_model(gid, &conv, ctx.db.clone(), model).await?
+				}
+				None => {
+					let (backend, _, model) = setup(gid, ctx.db.clone()).await?;
+					vec![Data::string(format!("Model: {} ({})", model, backend.name()))]
+				}
+			}
+		} else if args.first() == Some(&"!models") {
+			let (_, _, current) = setup(gid, ctx.db.clone()).await?;
+			let list: Vec<String> = models::all().iter()
+				.map(|m| format!("{} {}", if m.name == current { "*" } else { "-" }, m.describe()))
+				.collect();
+			vec![Data::string(format!("Models:\n{}", list.join("\n")))]
+		} else if args.first() == Some(&"!history") {
+			if args.get(1) == Some(&"pop") {
+				allow!(&ctx.sender, Identity::Owner); // Require owner to edit history
+			}
+			history_command(ctx, &args[1..]).await?
+		} else if args.first() == Some(&"!export") {
+			if ctx.group_id.is_some() {
+				allow!(&ctx.sender, Identity::Admin); // Require admin to export a group conversation
+			} else {
+				allow!(&ctx.sender, Identity::Owner); // Require owner to export in private
+			}
+			transfer::export_command(ctx, &args[1..]).await?
+		} else if args.first() == Some(&"!import") {
+			allow!(&ctx.sender, Identity::Owner); // Require owner to replace a conversation
+			transfer::import_command(ctx).await?
+		} else if args.first() == Some(&"!stream") {
+			let on = match args.get(1) {
+				Some(&"on") => true,
+				Some(&"off") => false,
+				_ => {
+					let on = stream::enabled(gid, ctx.db.clone()).await?;
+					return Ok(Some(vec![Data::string(format!("Streaming is {}", if on { "on" } else { "off" }))]));
+				}
+			};
+			allow!(&ctx.sender, Identity::Admin); // Require admin to toggle streaming
+			stream::set_enabled(gid, ctx.db.clone(), on).await?;
+			vec![Data::string(format!("Streaming turned {}", if on { "on" } else { "off" }))]
+		} else if args.first() == Some(&"!speak") {
+			let on = match args.get(1) {
+				Some(&"on") => true,
+				Some(&"off") => false,
+				_ => {
+					let persona = persona::active(gid, ctx.db.clone()).await?;
+					let voice = speech::voice_for(gid, ctx.db.clone(), &persona).await?;
+					return Ok(Some(vec![Data::string(match voice {
+						Some(v) => format!("Replies are spoken with voice {}", v),
+						None => "Replies are sent as text".to_string(),
+					})]));
+				}
+			};
+			allow!(&ctx.sender, Identity::Admin); // Require admin to toggle spoken replies
+			if !speech::enabled() {
+				return Ok(Some(vec![Data::string("Speech synthesis is not configured".to_string())]));
+			}
+			speech::set_speak(gid, ctx.db.clone(), on).await?;
+			vec![Data::string(format!("Spoken replies turned {}", if on { "on" } else { "off" }))]
+		} else if args.first() == Some(&"!memory") {
+			let (_, _, model) = setup(gid, ctx.db.clone()).await?;
+			let bot = bot_uid(&model);
+			if args.get(1) == Some(&"clear") {
+				allow!(&ctx.sender, Identity::Admin); // Require admin to drop memory
+				memory::clear(ctx.db.clone(), &conv, &bot).await?;
+				vec![Data::string("Memory cleared".to_string())]
+			} else {
+				match memory::get(ctx.db.clone(), &conv, &bot).await? {
+					Some(m) => vec![Data::string(format!("Memory of {}:\n{}", model, m))],
+					None => vec![Data::string("No memory yet".to_string())],
+				}
+			}
+		} else if args.first() == Some(&"!mode") {
+			match args.get(1) {
+				Some(name) => {
+					allow!(&ctx.sender, Identity::Admin); // Require admin to change the mode
+					let Some(mode) = thread::Mode::parse(name) else {
+						return Ok(Some(vec![Data::string("Usage: ~ai !mode [shared | per-user | per-thread]".to_string())]));
+					};
+					thread::set_mode(gid, ctx.db.clone(), mode).await?;
+					vec![Data::string(format!("Conversation mode set to {}", mode.as_str()))]
+				}
+				None => {
+					let mode = thread::mode(gid, ctx.db.clone()).await?;
+					vec![Data::string(format!("Conversation mode: {}", mode.as_str()))]
+				}
+			}
+		} else if args.first() == Some(&"!join") {
+			match &args[1..] {
+				[field, value] => {
+					allow!(&ctx.sender, Identity::Admin); // Require admin to tune auto-join
+					match gate::set_field(gid, ctx.db.clone(), field, value).await? {
+						Ok(()) => vec![Data::string(format!("Auto-join {} set to {}", field, value))],
+						Err(problem) => vec![Data::string(problem)],
+					}
+				}
+				[] => {
+					let c = gate::settings(gid, ctx.db.clone()).await?;
+					vec![Data::string(format!("Auto-join: {}\nprobability {}, cooldown {}s, rate {} per {}s, classifier {}",
+						if *AI_AUTO_JOIN.read().unwrap() { "on" } else { "off" },
+						c.probability, c.cooldown, c.rate, c.rate_window, if c.classifier { "on" } else { "off" }))]
+				}
+				_ => vec![Data::string(format!("Usage: ~ai !join [<{}> <value>]", gate::FIELDS.join("|")))],
+			}
+		} else if args.first() == Some(&"!moderation") {
+			return moderation_command(ctx, &args[1..]).await;
+		} else if args.first() == Some(&"!usage") {
+			return usage_command(ctx, &args[1..]).await;
+		} else if args.first() == Some(&"!tools") {
+			return tools_command(ctx, &args[1..]).await;
+		} else if args.first() == Some(&"!persona") {
+			return persona_command(ctx, &args[1..]).await;
+		} else if args.first() == Some(&"!template") {
+			return template_command(ctx, &args[1..]).await;
+		} else if args.first() == Some(&"!token") {
+			return token_command(ctx, &args[1..]).await;
+		} else if args.first() == Some(&"!backend") {
+			match args.get(1) {
+				Some(name) => {
+					allow!(&ctx.sender, Identity::Owner); // Require owner for backend
+					match backend::set_for_group(gid, ctx.db.clone(), name).await? {
+						Ok(()) => {
+							clear_record(&conv, ctx.db.clone(), "main").await?;
+							vec![Data::string(format!("Backend set to {}", name))]
+						}
+						Err(problem) => vec![Data::string(problem)],
+					}
+				}
+				None => {
+					let current = backend::for_group(gid, ctx.db.clone()).await?;
+					vec![Data::string(format!("Backend: {} (available: {})", current.name(), backend::names().join(", ")))]
+				}
+			}
+		} else {
+			answer(ctx, main_conversation(ctx, &args.join(" ")).await).await
+		};
+		Ok(Some(ret))
+	}
+}
@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+use redis::{AsyncCommands, Client};
+use log::info;
+
+use crate::config;
+use crate::handler::DynErr;
+
+/// A model groups may select with `~ai !model`.
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+	pub name: String,
+	pub backend: String,
+	/// Names the model's conversation state in `ai:{conv}:{bot}:*`.
+	pub bot_uid: String,
+	pub vision: bool,
+	pub context: Option<usize>,
+}
+
+impl ModelInfo {
+	pub fn describe(&self) -> String {
+		let mut out = format!("{} ({})", self.name, self.backend);
+		if self.vision {
+			out += " vision";
+		}
+		if let Some(c) = self.context {
+			out += &format!(" {}k", c / 1000);
+		}
+		out
+	}
+}
+
+static MODELS: OnceCell<Vec<ModelInfo>> = OnceCell::new();
+
+/// What Monica calls a model, and how conversation state was keyed before the catalogue.
+fn legacy_bot_uid(model: &str) -> String {
+	model.replace("-", "_").replace(".", "_")
+}
+
+/// Loads `[[ai.models]]`, or, without any, the backends' default models.
+pub fn init(config: &config::Ai) {
+	let mut models: Vec<ModelInfo> = config.models.iter().map(|m| ModelInfo {
+		name: m.name.clone(),
+		backend: m.backend.clone(),
+		bot_uid: m.bot_uid.clone().unwrap_or_else(|| legacy_bot_uid(&m.name)),
+		vision: m.vision,
+		context: m.context,
+	}).collect();
+	if models.is_empty() {
+		let mut defaults = vec![("monica", config.default_model.clone())];
+		if let Some(openai) = &config.openai {
+			defaults.push(("openai", openai.default_model.clone()));
+		}
+		for (backend, name) in defaults {
+			models.push(ModelInfo {
+				bot_uid: legacy_bot_uid(&name),
+				name,
+				backend: backend.to_string(),
+				vision: false,
+				context: None,
+			});
+		}
+	}
+	info!("AI models: {}", models.iter().map(|m| m.name.as_str()).collect::<Vec<&str>>().join(", "));
+	let _ = MODELS.set(models);
+}
+
+pub fn all() -> &'static [ModelInfo] {
+	MODELS.get().map(|m| m.as_slice()).unwrap_or_default()
+}
+
+pub fn get(name: &str) -> Option<&'static ModelInfo> {
+	all().iter().find(|m| m.name == name)
+}
+
+/// The catalogue's `bot_uid` for `model`. Models outside the catalogue, e.g. a
+/// persona's, fall back to the name with `-` and `.` replaced.
+pub fn bot_uid(model: &str) -> String {
+	get(model).map(|m| m.bot_uid.clone()).unwrap_or_else(|| legacy_bot_uid(model))
+}
+
+/// Moves group model choices from the old `ai:model:{gid}` keys to `ai:{gid}:model`.
+/// A choice already under the new key wins.
+pub async fn migrate(db: Arc<Client>) -> Result<usize, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let old: Vec<String> = conn.keys("ai:model:*").await?;
+	for key in &old {
+		let gid = key.trim_start_matches("ai:model:");
+		let _: bool = conn.rename_nx(key, format!("ai:{}:model", gid)).await?;
+		let _: () = conn.del(key).await?;
+	}
+	Ok(old.len())
+}
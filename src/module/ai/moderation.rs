@@ -0,0 +1,236 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::{Lazy, OnceCell};
+use redis::{AsyncCommands, Client};
+use regex::Regex;
+use serde_json::{json, Value};
+use log::{error, warn};
+
+use crate::config;
+use crate::handler::DynErr;
+use crate::plugin::MessageContext;
+
+const LOG_KEY: &str = "ai:moderation:log";
+const LOG_LEN: isize = 200;
+
+/// Digit runs long enough to be a phone or ID card number; QQ numbers are shorter.
+static LONG_DIGITS: Lazy<Regex> = Lazy::new(|| Regex::new(r"[0-9]{11,}[Xx]?").unwrap());
+static DASHED_PHONE: Lazy<Regex> = Lazy::new(|| Regex::new(r"1[3-9][0-9][- ][0-9]{4}[- ][0-9]{4}").unwrap());
+
+/// Which side of the conversation a text comes from.
+#[derive(Clone, Copy)]
+pub enum Kind {
+	Prompt,
+	Reply,
+}
+
+impl Kind {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Kind::Prompt => "prompt",
+			Kind::Reply => "reply",
+		}
+	}
+}
+
+pub enum Verdict {
+	/// The text to use, possibly with parts masked.
+	Allow(String),
+	/// The text must not be used; say this instead.
+	Refuse(String),
+}
+
+struct Moderator {
+	blocklist: Vec<Regex>,
+	refuse_replies: bool,
+	replacement: String,
+	refusal: String,
+	redact_pii: bool,
+	classifier: Option<Classifier>,
+}
+
+struct Classifier {
+	endpoint: String,
+	token: String,
+	model: Option<String>,
+	client: reqwest::Client,
+}
+
+static MODERATOR: OnceCell<Moderator> = OnceCell::new();
+
+/// One case-insensitive regex matching any of `keywords`, `None` without any.
+fn keyword_regex(keywords: &[String]) -> Option<Regex> {
+	let words: Vec<String> = keywords.iter().filter(|k| !k.is_empty()).map(|k| regex::escape(k)).collect();
+	if words.is_empty() {
+		// `(?i)` alone would match every text.
+		return None;
+	}
+	Regex::new(&format!("(?i){}", words.join("|"))).ok()
+}
+
+/// Compiles the blocklist. Patterns that fail to compile are logged and skipped.
+pub fn init(config: &config::Moderation) {
+	let mut blocklist = Vec::new();
+	blocklist.extend(keyword_regex(&config.keywords));
+	for p in &config.patterns {
+		match Regex::new(p) {
+			Ok(re) => blocklist.push(re),
+			Err(e) => error!("Invalid moderation pattern {}: {}", p, e),
+		}
+	}
+	let classifier = config.classifier_endpoint.as_ref().map(|endpoint| Classifier {
+		endpoint: endpoint.clone(),
+		token: config.classifier_token.clone(),
+		model: config.classifier_model.clone(),
+		client: reqwest::Client::builder().timeout(Duration::from_secs(30)).build().unwrap_or_default(),
+	});
+	let _ = MODERATOR.set(Moderator {
+		blocklist,
+		refuse_replies: config.action == "refuse",
+		replacement: config.replacement.clone(),
+		refusal: config.refusal.clone(),
+		redact_pii: config.redact_pii,
+		classifier,
+	});
+}
+
+/// Masks mainland mobile numbers and ID card numbers.
+fn redact(text: &str) -> String {
+	let text = DASHED_PHONE.replace_all(text, "[手机号已隐藏]");
+	LONG_DIGITS.replace_all(&text, |c: &regex::Captures| {
+		let s = &c[0];
+		let digits = s.trim_end_matches(['X', 'x']);
+		if s.len() == 11 && digits.len() == 11 && s.starts_with('1') && matches!(s.as_bytes()[1], b'3'..=b'9') {
+			"[手机号已隐藏]".to_string()
+		} else if s.len() == 18 && digits.len() >= 17 {
+			"[身份证号已隐藏]".to_string()
+		} else {
+			s.to_string()
+		}
+	}).into_owned()
+}
+
+impl Classifier {
+	/// The flagged categories, empty if the text passed.
+	async fn flagged(&self, text: &str) -> Result<Vec<String>, DynErr> {
+		let mut body = json!({ "input": text });
+		if let Some(m) = &self.model {
+			body["model"] = json!(m);
+		}
+		let mut request = self.client.post(&self.endpoint).json(&body);
+		if !self.token.is_empty() {
+			request = request.bearer_auth(&self.token);
+		}
+		let v: Value = request.send().await?.error_for_status()?.json().await?;
+		let result = &v["results"][0];
+		if result["flagged"] != true {
+			return Ok(vec![]);
+		}
+		let categories: Vec<String> = result["categories"].as_object().into_iter().flatten()
+			.filter(|(_, hit)| **hit == true)
+			.map(|(name, _)| name.clone())
+			.collect();
+		Ok(if categories.is_empty() { vec!["flagged".to_string()] } else { categories })
+	}
+}
+
+/// Runs `text` through the blocklist, PII redaction and the classifier.
+///
+/// Prompts with a blocklist hit are refused; replies are masked or refused per `action`.
+/// A failing classifier lets the text through, so an outage doesn't silence the bot.
+pub async fn check(ctx: &MessageContext, kind: Kind, text: &str) -> Result<Verdict, DynErr> {
+	let Some(m) = MODERATOR.get() else {
+		return Ok(Verdict::Allow(text.to_string()));
+	};
+
+	let mut out = text.to_string();
+	for re in &m.blocklist {
+		if let Some(hit) = re.find(&out) {
+			if matches!(kind, Kind::Prompt) || m.refuse_replies {
+				log(ctx, kind, &format!("blocklist: {}", hit.as_str()), text).await;
+				return Ok(Verdict::Refuse(m.refusal.clone()));
+			}
+			out = re.replace_all(&out, m.replacement.as_str()).into_owned();
+		}
+	}
+	if out != text {
+		log(ctx, kind, "blocklist: masked", text).await;
+	}
+	if m.redact_pii {
+		out = redact(&out);
+	}
+	if let Some(c) = &m.classifier {
+		match c.flagged(&out).await {
+			Ok(categories) if !categories.is_empty() => {
+				log(ctx, kind, &format!("classifier: {}", categories.join(",")), text).await;
+				return Ok(Verdict::Refuse(m.refusal.clone()));
+			}
+			Ok(_) => {}
+			Err(e) => warn!("[{} moderation] classifier failed: {:?}", ctx.msg_id, e),
+		}
+	}
+	Ok(Verdict::Allow(out))
+}
+
+/// Keeps the original `text` for the owner to review with `~ai !moderation`.
+async fn log(ctx: &MessageContext, kind: Kind, reason: &str, text: &str) {
+	warn!("[{} moderation] {} {}: {}", ctx.msg_id, kind.as_str(), reason, text);
+	let entry = json!({
+		"ts": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+		"gid": ctx.group_id.unwrap_or_default(),
+		"user": ctx.user_id,
+		"kind": kind.as_str(),
+		"reason": reason,
+		"text": text.chars().take(500).collect::<String>(),
+	});
+	let stored: Result<(), DynErr> = async {
+		let mut conn = ctx.db.get_multiplexed_async_connection().await?;
+		let _: () = conn.lpush(LOG_KEY, entry.to_string()).await?;
+		let _: () = conn.ltrim(LOG_KEY, 0, LOG_LEN - 1).await?;
+		Ok(())
+	}.await;
+	if let Err(e) = stored {
+		error!("[{} moderation] failed to log: {:?}", ctx.msg_id, e);
+	}
+}
+
+/// The newest `n` logged hits, newest first.
+pub async fn recent(db: Arc<Client>, n: usize) -> Result<Vec<Value>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let raw: Vec<String> = conn.lrange(LOG_KEY, 0, n as isize - 1).await?;
+	Ok(raw.iter().filter_map(|r| serde_json::from_str(r).ok()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn keywords_match_case_insensitively() {
+		let re = keyword_regex(&["Spam".to_string(), "a.b".to_string()]).unwrap();
+		assert!(re.is_match("no SPAM please"));
+		assert!(re.is_match("a.b"));
+		assert!(!re.is_match("axb"));
+	}
+
+	#[test]
+	fn empty_keywords_block_nothing() {
+		assert!(keyword_regex(&[]).is_none());
+		assert!(keyword_regex(&[String::new(), String::new()]).is_none());
+	}
+
+	#[test]
+	fn redacts_phones_and_id_cards() {
+		assert_eq!(redact("打13812345678找我"), "打[手机号已隐藏]找我");
+		assert_eq!(redact("138-1234-5678"), "[手机号已隐藏]");
+		assert_eq!(redact("11010519491231002X"), "[身份证号已隐藏]");
+	}
+
+	#[test]
+	fn keeps_qq_numbers() {
+		assert_eq!(redact("加我QQ 12345"), "加我QQ 12345");
+		assert_eq!(redact("QQ3141592653"), "QQ3141592653");
+		assert_eq!(redact("订单12345678901234567890"), "订单12345678901234567890");
+	}
+}
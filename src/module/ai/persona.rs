@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use redis::{AsyncCommands, Client};
+
+use crate::constants::INIT_PROMPT;
+use crate::handler::DynErr;
+
+/// Editable persona fields, as accepted by `~ai !persona set`.
+pub const FIELDS: [&str; 6] = ["prompt", "name", "model", "temperature", "style", "voice"];
+
+/// A named personality: what the AI is told on every turn and how it should answer.
+/// Stored as the Redis hash `ai:persona:{name}`.
+#[derive(Debug, Clone, Default)]
+pub struct Persona {
+	pub name: String,
+	pub system_prompt: String,
+	pub display_name: String,
+	/// Preferred model, used unless the group picked one with `~ai !model`.
+	pub model: Option<String>,
+	pub temperature: Option<f32>,
+	/// Free-form reply style, e.g. "简短口语化".
+	pub style: String,
+	/// TTS voice the persona speaks its replies with, unless the group turned speech off.
+	pub voice: Option<String>,
+}
+
+impl Persona {
+	/// Used by groups without a persona: the `init_prompt` from config.
+	pub fn fallback() -> Persona {
+		Persona {
+			name: "default".to_string(),
+			system_prompt: INIT_PROMPT.read().unwrap().clone(),
+			..Persona::default()
+		}
+	}
+
+	/// The system prompt sent with every turn.
+	pub fn system(&self) -> String {
+		let mut system = self.system_prompt.clone();
+		if !self.display_name.is_empty() {
+			system += &format!("\n你的名字是{}。", self.display_name);
+		}
+		if !self.style.is_empty() {
+			system += &format!("\n回复风格：{}", self.style);
+		}
+		system
+	}
+
+	fn from_hash(name: &str, h: HashMap<String, String>) -> Persona {
+		Persona {
+			name: name.to_string(),
+			system_prompt: h.get("prompt").cloned().unwrap_or_default(),
+			display_name: h.get("name").cloned().unwrap_or_default(),
+			model: h.get("model").filter(|m| !m.is_empty()).cloned(),
+			temperature: h.get("temperature").and_then(|t| t.parse().ok()),
+			style: h.get("style").cloned().unwrap_or_default(),
+			voice: h.get("voice").filter(|v| !v.is_empty()).cloned(),
+		}
+	}
+
+	pub fn describe(&self) -> String {
+		format!(
+			"{} ({})\nmodel: {}\ntemperature: {}\nstyle: {}\nvoice: {}\nprompt: {}",
+			self.name,
+			if self.display_name.is_empty() { "-" } else { &self.display_name },
+			self.model.as_deref().unwrap_or("-"),
+			self.temperature.map(|t| t.to_string()).unwrap_or("-".to_string()),
+			if self.style.is_empty() { "-" } else { &self.style },
+			self.voice.as_deref().unwrap_or("-"),
+			self.system_prompt.chars().take(200).collect::<String>(),
+		)
+	}
+}
+
+fn key(name: &str) -> String {
+	format!("ai:persona:{}", name)
+}
+
+pub async fn get(db: Arc<Client>, name: &str) -> Result<Option<Persona>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let h: HashMap<String, String> = conn.hgetall(key(name)).await?;
+	if h.is_empty() {
+		return Ok(None);
+	}
+	Ok(Some(Persona::from_hash(name, h)))
+}
+
+pub async fn list(db: Arc<Client>) -> Result<Vec<String>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let mut names: Vec<String> = conn.smembers("ai:personas").await?;
+	names.sort();
+	Ok(names)
+}
+
+/// Sets one field, creating the persona if needed, or says what is wrong with it.
+pub async fn set_field(db: Arc<Client>, name: &str, field: &str, value: &str) -> Result<Result<(), String>, DynErr> {
+	if !FIELDS.contains(&field) {
+		return Ok(Err(format!("Unknown field {}, expected one of: {}", field, FIELDS.join(", "))));
+	}
+	if field == "temperature" {
+		match value.parse::<f32>() {
+			Ok(t) if (0.0..=2.0).contains(&t) => {}
+			_ => return Ok(Err("Temperature must be a number between 0 and 2".to_string())),
+		}
+	}
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.hset(key(name), field, value).await?;
+	let _: () = conn.sadd("ai:personas", name).await?;
+	Ok(Ok(()))
+}
+
+pub async fn delete(db: Arc<Client>, name: &str) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.del(key(name)).await?;
+	let _: () = conn.srem("ai:personas", name).await?;
+	Ok(())
+}
+
+/// The persona `gid` switched to, or the config fallback.
+pub async fn active(gid: u64, db: Arc<Client>) -> Result<Persona, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let name: Option<String> = conn.get(format!("ai:{}:persona", gid)).await?;
+	match name {
+		Some(name) => Ok(get(db, &name).await?.unwrap_or_else(Persona::fallback)),
+		None => Ok(Persona::fallback()),
+	}
+}
+
+/// Switches `gid` to `name`; `default` goes back to the config prompt.
+/// Unknown names are refused with the reason.
+pub async fn activate(gid: u64, db: Arc<Client>, name: &str) -> Result<Result<(), String>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	if name == "default" {
+		let _: () = conn.del(format!("ai:{}:persona", gid)).await?;
+		return Ok(Ok(()));
+	}
+	if get(db.clone(), name).await?.is_none() {
+		return Ok(Err(format!("No persona named {}", name)));
+	}
+	let _: () = conn.set(format!("ai:{}:persona", gid), name).await?;
+	Ok(Ok(()))
+}
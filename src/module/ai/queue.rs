@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+
+use once_cell::sync::Lazy;
+use tokio::sync::{Mutex, OwnedMutexGuard, Semaphore, SemaphorePermit};
+use tokio::time::{timeout, Duration};
+use log::error;
+
+use crate::constants::AI_MAX_CONCURRENT;
+use crate::dto::Data;
+use crate::handler::DynErr;
+use crate::plugin::MessageContext;
+
+/// How long a request may wait for its turn before giving up.
+const MAX_WAIT: Duration = Duration::from_secs(120);
+
+#[derive(Default)]
+struct ConvLock {
+	lock: Arc<Mutex<()>>,
+	waiting: AtomicUsize,
+}
+
+/// One lock per conversation, so the `prev/now/count` chain and the history
+/// see one exchange at a time. Entries are dropped when nobody holds or waits.
+static CONVERSATIONS: Lazy<StdMutex<HashMap<String, Arc<ConvLock>>>> = Lazy::new(|| StdMutex::new(HashMap::new()));
+
+/// Caps backend requests in flight across all conversations.
+static BACKEND: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(*AI_MAX_CONCURRENT.read().unwrap()));
+static BACKEND_WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// Held while a conversation talks to the backend.
+pub struct Ticket {
+	key: String,
+	conv: Option<Arc<ConvLock>>,
+	_guard: OwnedMutexGuard<()>,
+	_permit: SemaphorePermit<'static>,
+}
+
+impl Drop for Ticket {
+	fn drop(&mut self) {
+		let mut map = CONVERSATIONS.lock().unwrap();
+		// Our handle plus the map's: nobody else is waiting for this conversation.
+		if self.conv.take().is_some_and(|c| Arc::strong_count(&c) <= 2) {
+			map.remove(&self.key);
+		}
+	}
+}
+
+async fn tell(ctx: &MessageContext, text: String) {
+	if ctx.outbox.is_none() {
+		return;
+	}
+	if let Err(e) = ctx.send(vec![Data::reply(ctx.msg_id), Data::string(text)]).await {
+		error!("[{} <=queue] {:?}", ctx.msg_id, e);
+	}
+}
+
+/// Waits for exclusive use of conversation `key` and a backend slot, telling the
+/// sender their place in line when they can't go first.
+pub async fn acquire(ctx: &MessageContext, key: &str) -> Result<Ticket, DynErr> {
+	let conv = CONVERSATIONS.lock().unwrap().entry(key.to_string()).or_default().clone();
+
+	let guard = match conv.lock.clone().try_lock_owned() {
+		Ok(guard) => guard,
+		Err(_) => {
+			let ahead = conv.waiting.fetch_add(1, Ordering::SeqCst) + 1;
+			tell(ctx, format!("前面还有 {} 条消息在等待回复，请稍候", ahead)).await;
+			let guard = timeout(MAX_WAIT, conv.lock.clone().lock_owned()).await;
+			conv.waiting.fetch_sub(1, Ordering::SeqCst);
+			guard.map_err(|_| "Timeout waiting for conversation lock")?
+		}
+	};
+
+	let permit = match BACKEND.try_acquire() {
+		Ok(permit) => permit,
+		Err(_) => {
+			let ahead = BACKEND_WAITING.fetch_add(1, Ordering::SeqCst) + 1;
+			tell(ctx, format!("AI 正忙，你排在第 {} 位", ahead)).await;
+			let permit = timeout(MAX_WAIT, BACKEND.acquire()).await;
+			BACKEND_WAITING.fetch_sub(1, Ordering::SeqCst);
+			permit.map_err(|_| "Timeout waiting for a free AI slot")??
+		}
+	};
+
+	Ok(Ticket { key: key.to_string(), conv: Some(conv), _guard: guard, _permit: permit })
+}
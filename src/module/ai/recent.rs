@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+
+use crate::constants::AI_RECENT_MESSAGES;
+use crate::handler::DynErr;
+use crate::plugin::MessageContext;
+
+/// Characters kept of each buffered message.
+const MAX_CHARS: usize = 150;
+
+/// Messages starting with these are commands, for this bot or another, not conversation.
+const COMMAND_PREFIXES: [char; 3] = ['~', '/', '／'];
+
+/// One message of a group's rolling buffer `ai:{gid}:recent`.
+#[derive(Serialize, Deserialize)]
+pub struct Line {
+	pub msg_id: u64,
+	pub speaker: String,
+	pub text: String,
+	pub ts: u64,
+}
+
+fn key(gid: u64) -> String {
+	format!("ai:{}:recent", gid)
+}
+
+/// The group card if set, else the nickname.
+fn speaker(ctx: &MessageContext) -> String {
+	ctx.sender.get("card").and_then(|c| c.as_str()).filter(|c| !c.is_empty())
+		.unwrap_or(&ctx.nickname)
+		.to_string()
+}
+
+/// Appends `ctx` to its group's buffer, unless it is a command or empty.
+/// `voice` is the transcript of its voice messages, if any.
+pub async fn record(ctx: &MessageContext, voice: &str) -> Result<(), DynErr> {
+	let len = *AI_RECENT_MESSAGES.read().unwrap();
+	let Some(gid) = ctx.group_id else {
+		return Ok(());
+	};
+	let text = ctx.text.trim();
+	if len == 0 || text.starts_with(COMMAND_PREFIXES) {
+		return Ok(());
+	}
+	let mut content: String = text.chars().take(MAX_CHARS).collect();
+	if !voice.is_empty() {
+		content += &format!("[语音]{}", voice.chars().take(MAX_CHARS).collect::<String>());
+	}
+	for i in &ctx.images {
+		content += &format!("[图片{}]", i.summary);
+	}
+	if content.is_empty() {
+		return Ok(());
+	}
+	let line = Line {
+		msg_id: ctx.msg_id,
+		speaker: speaker(ctx),
+		text: content,
+		ts: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+	};
+	let mut conn = ctx.db.get_multiplexed_async_connection().await?;
+	let _: () = conn.rpush(key(gid), serde_json::to_string(&line)?).await?;
+	let _: () = conn.ltrim(key(gid), -(len as isize), -1).await?;
+	let _: () = conn.expire(key(gid), 86400).await?;
+	Ok(())
+}
+
+/// The buffered messages of `gid` before `msg_id`, oldest first.
+pub async fn load(gid: u64, db: Arc<Client>, msg_id: u64) -> Result<Vec<Line>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let raw: Vec<String> = conn.lrange(key(gid), 0, -1).await?;
+	Ok(raw.iter()
+		.filter_map(|l| serde_json::from_str::<Line>(l).ok())
+		.take_while(|l| l.msg_id != msg_id)
+		.collect())
+}
+
+/// `system` with a compact transcript of what the group said before `ctx`.
+pub async fn with_transcript(system: String, ctx: &MessageContext) -> Result<String, DynErr> {
+	let Some(gid) = ctx.group_id else {
+		return Ok(system);
+	};
+	let lines = load(gid, ctx.db.clone(), ctx.msg_id).await?;
+	if lines.is_empty() {
+		return Ok(system);
+	}
+	let mut out = system + "\n群里最近的聊天记录，供理解上下文：";
+	for l in lines {
+		let when = chrono::DateTime::from_timestamp(l.ts as i64, 0)
+			.map(|d| d.with_timezone(&chrono::Local).format("%H:%M").to_string())
+			.unwrap_or_default();
+		out += &format!("\n[{}] {}：{}", when, l.speaker, l.text);
+	}
+	Ok(out)
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use once_cell::sync::{Lazy, OnceCell};
+use rand::Rng;
+use log::{error, warn};
+
+use super::backend::error::{classify, AiError, ErrorKind};
+use super::template;
+use super::backend::{AiBackend, ChatOutcome, ChatRequest, ChatStream, ToolSpec};
+use super::backend::vision::{VisionBackend, VisionRequest};
+use crate::config;
+use crate::constants::OWNER_ID;
+use crate::dto::Data;
+use crate::handler::DynErr;
+use crate::plugin::MessageContext;
+
+/// Seconds between two private reports of the same kind of error to the owner.
+const NOTIFY_INTERVAL: u64 = 60;
+
+static CONFIG: OnceCell<(config::Retry, String)> = OnceCell::new();
+
+pub fn init(config: &config::Retry, locale: &str) {
+	let _ = CONFIG.set((config.clone(), locale.to_string()));
+}
+
+fn config() -> config::Retry {
+	CONFIG.get().map(|c| c.0.clone()).unwrap_or_default()
+}
+
+#[derive(Default)]
+struct Breaker {
+	failures: u32,
+	open_until: Option<Instant>,
+	/// When the one request let through after the cooldown started, while it is out.
+	probe: Option<Instant>,
+}
+
+/// One breaker per backend name.
+static BREAKERS: Lazy<StdMutex<HashMap<String, Breaker>>> = Lazy::new(|| StdMutex::new(HashMap::new()));
+
+/// Fails fast while `backend`'s circuit is open. After the cooldown a single request
+/// is let through while the others keep failing fast; its failure opens the circuit
+/// again, its success closes it. A probe that never reports back is given up on after
+/// another cooldown.
+fn admit(backend: &str) -> Result<(), DynErr> {
+	let cooldown = Duration::from_secs(config().breaker_cooldown);
+	let mut breakers = BREAKERS.lock().unwrap();
+	let b = breakers.entry(backend.to_string()).or_default();
+	let now = Instant::now();
+	match b.open_until {
+		None => Ok(()),
+		Some(until) if until > now => {
+			Err(AiError::boxed(ErrorKind::CircuitOpen, format!("{} circuit open for {}s more", backend, (until - now).as_secs())))
+		}
+		Some(_) if b.probe.is_some_and(|p| now - p < cooldown) => {
+			Err(AiError::boxed(ErrorKind::CircuitOpen, format!("{} circuit half-open, waiting for its probe", backend)))
+		}
+		Some(_) => {
+			b.probe = Some(now);
+			Ok(())
+		}
+	}
+}
+
+fn succeeded(backend: &str) {
+	BREAKERS.lock().unwrap().insert(backend.to_string(), Breaker::default());
+}
+
+fn failed(backend: &str, kind: ErrorKind) {
+	let c = config();
+	let mut breakers = BREAKERS.lock().unwrap();
+	let b = breakers.entry(backend.to_string()).or_default();
+	// Rejected requests say nothing about the backend's health; a probe that ends
+	// like that just makes way for the next one.
+	if matches!(kind, ErrorKind::CircuitOpen | ErrorKind::Other) {
+		if kind == ErrorKind::Other {
+			b.probe = None;
+		}
+		return;
+	}
+	b.probe = None;
+	b.failures += 1;
+	if c.breaker_threshold > 0 && b.failures >= c.breaker_threshold {
+		warn!("AI backend {} failed {} times in a row, pausing it for {}s", backend, b.failures, c.breaker_cooldown);
+		b.open_until = Some(Instant::now() + Duration::from_secs(c.breaker_cooldown));
+	}
+}
+
+/// Exponential backoff with jitter; rate limits wait twice as long.
+fn backoff(attempt: u32, kind: ErrorKind) -> Duration {
+	let base = config().base_delay_ms;
+	let mut ms = base.saturating_mul(1 << attempt.min(10));
+	if kind == ErrorKind::RateLimited {
+		ms *= 2;
+	}
+	Duration::from_millis(ms + rand::rng().random_range(0..=base))
+}
+
+/// Retries transient failures of the wrapped backend and trips its circuit breaker,
+/// chat and vision alike.
+///
+/// A stream is only retried until its first chunk: after that, text may
+/// already be in the chat.
+pub struct Resilient<B: ?Sized>(pub Arc<B>);
+
+#[async_trait]
+impl<B: AiBackend + ?Sized> AiBackend for Resilient<B> {
+	fn name(&self) -> &'static str {
+		self.0.name()
+	}
+
+	fn default_model(&self) -> String {
+		self.0.default_model()
+	}
+
+	async fn chat_stream(&self, req: &ChatRequest) -> Result<ChatStream, DynErr> {
+		let name = self.name();
+		let max_retries = config().max_retries;
+		let mut attempt = 0;
+		loop {
+			admit(name)?;
+			let first = match self.0.chat_stream(req).await {
+				Ok(mut stream) => match stream.next().await {
+					Some(Ok(chunk)) => Ok(Some((chunk, stream))),
+					None => Ok(None),
+					Some(Err(e)) => Err(e),
+				},
+				Err(e) => Err(e),
+			};
+			let e = match first {
+				Ok(None) => {
+					succeeded(name);
+					return Ok(futures::stream::empty().boxed());
+				}
+				Ok(Some((chunk, rest))) => {
+					succeeded(name);
+					let rest = rest.inspect(move |c| {
+						if let Err(e) = c {
+							failed(name, classify(e));
+						}
+					});
+					return Ok(futures::stream::once(async move { Ok(chunk) }).chain(rest).boxed());
+				}
+				Err(e) => e,
+			};
+			let kind = classify(&e);
+			failed(name, kind);
+			if !kind.transient() || attempt >= max_retries {
+				return Err(e);
+			}
+			let wait = backoff(attempt, kind);
+			warn!("[{} {}] {} (attempt {}), retrying in {:?}", req.conv, name, e, attempt + 1, wait);
+			tokio::time::sleep(wait).await;
+			attempt += 1;
+		}
+	}
+
+	async fn chat_tools(&self, req: &ChatRequest, tools: &[ToolSpec]) -> Result<ChatOutcome, DynErr> {
+		let name = self.name();
+		let max_retries = config().max_retries;
+		let mut attempt = 0;
+		loop {
+			admit(name)?;
+			let e = match self.0.chat_tools(req, tools).await {
+				Ok(outcome) => {
+					succeeded(name);
+					return Ok(outcome);
+				}
+				Err(e) => e,
+			};
+			let kind = classify(&e);
+			failed(name, kind);
+			if !kind.transient() || attempt >= max_retries {
+				return Err(e);
+			}
+			let wait = backoff(attempt, kind);
+			warn!("[{} {}] {} (attempt {}), retrying in {:?}", req.conv, name, e, attempt + 1, wait);
+			tokio::time::sleep(wait).await;
+			attempt += 1;
+		}
+	}
+}
+
+#[async_trait]
+impl<B: VisionBackend + ?Sized> VisionBackend for Resilient<B> {
+	fn name(&self) -> &'static str {
+		self.0.name()
+	}
+
+	fn model(&self) -> String {
+		self.0.model()
+	}
+
+	async fn describe(&self, req: &VisionRequest<'_>) -> Result<String, DynErr> {
+		// A breaker of its own: the vision endpoint or model can be down while chat is fine.
+		let breaker = format!("{} vision", self.name());
+		let max_retries = config().max_retries;
+		let mut attempt = 0;
+		loop {
+			admit(&breaker)?;
+			let e = match self.0.describe(req).await {
+				Ok(text) => {
+					succeeded(&breaker);
+					return Ok(text);
+				}
+				Err(e) => e,
+			};
+			let kind = classify(&e);
+			failed(&breaker, kind);
+			if !kind.transient() || attempt >= max_retries {
+				return Err(e);
+			}
+			let wait = backoff(attempt, kind);
+			warn!("[{} {}] {} (attempt {}), retrying in {:?}", req.img.file, breaker, e, attempt + 1, wait);
+			tokio::time::sleep(wait).await;
+			attempt += 1;
+		}
+	}
+}
+
+/// What users are told instead of the raw error, unless a template replaces it.
+pub fn message(kind: ErrorKind) -> &'static str {
+	let en = CONFIG.get().is_some_and(|c| c.1 == "en");
+	match (kind, en) {
+		(ErrorKind::AuthExpired, false) => "AI 服务的登录已失效，已经通知主人处理，请稍后再试",
+		(ErrorKind::AuthExpired, true) => "The AI service login has expired. The owner has been told; please try again later.",
+		(ErrorKind::RateLimited, false) => "问的人太多了，AI 需要歇一会儿，请稍后再试",
+		(ErrorKind::RateLimited, true) => "The AI is getting too many requests. Please try again in a moment.",
+		(ErrorKind::Timeout, false) => "AI 想得太久没有回应，请稍后再试",
+		(ErrorKind::Timeout, true) => "The AI took too long to answer. Please try again later.",
+		(ErrorKind::CircuitOpen, false) => "AI 服务暂时不可用，请过一会儿再试",
+		(ErrorKind::CircuitOpen, true) => "The AI service is unavailable right now. Please try again in a while.",
+		(_, false) => "出了点问题，已经通知主人处理，请稍后再试",
+		(_, true) => "Something went wrong. The owner has been told; please try again later.",
+	}
+}
+
+/// Turns a failed AI turn, or any failed command, into a friendly reply, and reports
+/// the details to the owner in private, at most once a minute per kind of error.
+pub async fn friendly(ctx: &MessageContext, e: DynErr) -> Vec<Data> {
+	let kind = classify(&e);
+	error!("[{} <=ai_err] {} {:?}", ctx.msg_id, kind.as_str(), e);
+	if kind != ErrorKind::CircuitOpen {
+		if let Err(e) = notify_owner(ctx, kind, &e).await {
+			error!("[{} <=ai_err] failed to notify owner: {:?}", ctx.msg_id, e);
+		}
+	}
+	vec![Data::reply(ctx.msg_id), Data::string(user_message(ctx, kind).await)]
+}
+
+async fn user_message(ctx: &MessageContext, kind: ErrorKind) -> String {
+	let gid = ctx.group_id.unwrap_or_default();
+	match template::get(gid, ctx.db.clone(), template::error_name(kind)).await {
+		Ok(Some(t)) => {
+			let mut vars = template::vars(ctx).await;
+			vars.insert("error", kind.as_str().to_string());
+			template::render(&t, &vars)
+		}
+		_ => message(kind).to_string(),
+	}
+}
+
+async fn notify_owner(ctx: &MessageContext, kind: ErrorKind, e: &DynErr) -> Result<(), DynErr> {
+	let Some(outbox) = &ctx.outbox else {
+		return Ok(());
+	};
+	let mut conn = ctx.db.get_multiplexed_async_connection().await?;
+	let fresh: bool = redis::cmd("SET").arg(format!("ai:errors:notified:{}", kind.as_str())).arg(1)
+		.arg("NX").arg("EX").arg(NOTIFY_INTERVAL)
+		.query_async::<Option<String>>(&mut conn).await?
+		.is_some();
+	if !fresh {
+		return Ok(());
+	}
+	let mut detail = format!("Error ({}) in {} from {}:\n{:?}",
+		kind.as_str(), ctx.group_id.map(|g| format!("group {}", g)).unwrap_or_else(|| "private".to_string()), ctx.user_id, e);
+	if kind == ErrorKind::AuthExpired {
+		detail += "\nSend ~ai !token <session_id> here to replace the Monica session.";
+	}
+	let owner = *OWNER_ID.read().unwrap();
+	crate::handler::send(crate::handler::reply_to(None, owner, vec![Data::string(detail)]), outbox.clone()).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn expire(backend: &'static str) {
+		BREAKERS.lock().unwrap().get_mut(backend).unwrap().open_until = Some(Instant::now() - Duration::from_secs(1));
+	}
+
+	#[test]
+	fn breaker_opens_after_threshold() {
+		let name = "test-open";
+		for _ in 0..config().breaker_threshold {
+			assert!(admit(name).is_ok());
+			failed(name, ErrorKind::Timeout);
+		}
+		let e = admit(name).unwrap_err();
+		assert_eq!(classify(&e), ErrorKind::CircuitOpen);
+	}
+
+	#[test]
+	fn half_open_lets_one_probe_through() {
+		let name = "test-probe";
+		for _ in 0..config().breaker_threshold {
+			failed(name, ErrorKind::Timeout);
+		}
+		expire(name);
+		assert!(admit(name).is_ok());
+		assert!(admit(name).is_err());
+		succeeded(name);
+		assert!(admit(name).is_ok());
+		assert!(admit(name).is_ok());
+	}
+
+	#[test]
+	fn failed_probe_reopens() {
+		let name = "test-reopen";
+		for _ in 0..config().breaker_threshold {
+			failed(name, ErrorKind::Timeout);
+		}
+		expire(name);
+		assert!(admit(name).is_ok());
+		failed(name, ErrorKind::ServerError);
+		assert!(admit(name).is_err());
+	}
+}
@@ -0,0 +1,75 @@
+use serde_json::json;
+
+use super::backend::monica::client;
+use crate::handler::DynErr;
+use crate::module::ai_img::download_at_most;
+use crate::plugin::MessageContext;
+
+/// Characters per node when a long text is sent as a forward message.
+const NODE_CHARS: usize = 3000;
+
+/// The name and text of the file shared in the message `ctx` replies to,
+/// if it is UTF-8 text of at most `max_bytes`.
+pub async fn replied_text(ctx: &MessageContext, max_bytes: u64) -> Result<Option<(String, String)>, DynErr> {
+	let Some(id) = super::thread::replied_to(ctx) else {
+		return Ok(None);
+	};
+	let msg = ctx.call_api("get_msg", json!({ "message_id": id })).await?;
+	let Some(file) = msg["message"].as_array().and_then(|m| m.iter().find(|s| s["type"] == "file")) else {
+		return Ok(None);
+	};
+	let file = &file["data"];
+	let name = file["file"].as_str().or(file["name"].as_str()).unwrap_or("file").to_string();
+	let size = file["file_size"].as_str().and_then(|s| s.parse().ok()).or(file["file_size"].as_u64()).unwrap_or_default();
+	if size > max_bytes {
+		return Err(format!("{} is larger than {} KiB", name, max_bytes / 1024).into());
+	}
+	let mut url = file["url"].as_str().filter(|u| !u.is_empty()).map(str::to_string);
+	if url.is_none() {
+		let file_id = file["file_id"].as_str().unwrap_or_default();
+		url = match ctx.group_id {
+			Some(gid) => ctx.call_api("get_group_file_url", json!({ "group_id": gid, "file_id": file_id, "busid": file["busid"] })).await?,
+			None => ctx.call_api("get_file", json!({ "file_id": file_id })).await?,
+		}["url"].as_str().map(str::to_string);
+	}
+	let url = url.ok_or_else(|| format!("No download URL for {}", name))?;
+	let bytes = download_at_most(client(), &url, max_bytes as usize).await?;
+	let text = String::from_utf8(bytes).map_err(|_| format!("{} is not a UTF-8 text file", name))?;
+	Ok(Some((name, text)))
+}
+
+/// Uploads `content` as file `name` to the chat `ctx` came from.
+///
+/// OneBot reads the file from its own disk, so this only works when it runs
+/// on the same machine as the bot.
+pub async fn upload(ctx: &MessageContext, name: &str, content: &str) -> Result<(), DynErr> {
+	let path = std::env::temp_dir().join(format!("{}-{}", uuid::Uuid::new_v4().simple(), name));
+	tokio::fs::write(&path, content).await?;
+	let file = path.to_string_lossy().to_string();
+	let ret = match ctx.group_id {
+		Some(gid) => ctx.call_api("upload_group_file", json!({ "group_id": gid, "file": file, "name": name })).await,
+		None => ctx.call_api("upload_private_file", json!({ "user_id": ctx.user_id, "file": file, "name": name })).await,
+	};
+	let _ = tokio::fs::remove_file(&path).await;
+	ret.map(|_| ())
+}
+
+/// Sends `nodes`, each a speaker and a text, as one forward message.
+/// Texts longer than a node holds are split.
+pub async fn forward(ctx: &MessageContext, nodes: &[(String, String)]) -> Result<(), DynErr> {
+	let mut messages = Vec::new();
+	for (name, text) in nodes {
+		let chars: Vec<char> = text.chars().collect();
+		for piece in chars.chunks(NODE_CHARS) {
+			messages.push(json!({
+				"type": "node",
+				"data": { "name": name, "uin": ctx.user_id.to_string(), "content": piece.iter().collect::<String>() },
+			}));
+		}
+	}
+	match ctx.group_id {
+		Some(gid) => ctx.call_api("send_group_forward_msg", json!({ "group_id": gid, "messages": messages })).await?,
+		None => ctx.call_api("send_private_forward_msg", json!({ "user_id": ctx.user_id, "messages": messages })).await?,
+	};
+	Ok(())
+}
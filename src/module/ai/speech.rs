@@ -0,0 +1,137 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use redis::{AsyncCommands, Client};
+use serde_json::json;
+use log::{info, warn};
+
+use super::moderation;
+use super::persona::{self, Persona};
+use crate::config::{self, Config};
+use crate::dto::Data;
+use crate::handler::DynErr;
+use crate::plugin::{MessageContext, Plugin};
+
+/// An OpenAI-compatible `/audio/speech` API.
+struct Speaker {
+	endpoint: String,
+	token: String,
+	model: String,
+	voice: String,
+	max_chars: usize,
+	client: reqwest::Client,
+}
+
+static SPEAKER: OnceCell<Speaker> = OnceCell::new();
+
+pub fn init(config: &config::Ai) {
+	let t = &config.tts;
+	let endpoint = t.endpoint.clone().or_else(|| config.openai.as_ref().map(|o| o.endpoint.clone()));
+	let Some(endpoint) = endpoint else {
+		return;
+	};
+	let token = t.token.clone().or_else(|| config.openai.as_ref().map(|o| o.token.clone())).unwrap_or_default();
+	let _ = SPEAKER.set(Speaker {
+		endpoint: endpoint.trim_end_matches('/').to_string(),
+		token,
+		model: t.model.clone(),
+		voice: t.voice.clone(),
+		max_chars: t.max_chars,
+		client: reqwest::Client::builder().timeout(Duration::from_secs(60)).build().unwrap_or_default(),
+	});
+}
+
+pub fn enabled() -> bool {
+	SPEAKER.get().is_some()
+}
+
+/// The voice replies in `gid` are spoken with, `None` for text replies.
+/// `~ai !speak on|off` decides; without it, personas with a `voice` speak.
+pub async fn voice_for(gid: u64, db: Arc<Client>, persona: &Persona) -> Result<Option<String>, DynErr> {
+	let Some(s) = SPEAKER.get() else {
+		return Ok(None);
+	};
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let setting: Option<String> = conn.get(format!("ai:{}:speak", gid)).await?;
+	Ok(match setting.as_deref() {
+		Some("off") => None,
+		Some(_) => Some(persona.voice.clone().unwrap_or_else(|| s.voice.clone())),
+		None => persona.voice.clone(),
+	})
+}
+
+pub async fn set_speak(gid: u64, db: Arc<Client>, on: bool) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set(format!("ai:{}:speak", gid), if on { "on" } else { "off" }).await?;
+	Ok(())
+}
+
+/// `text` spoken with `voice`, as a record segment.
+pub async fn synthesize(text: &str, voice: &str) -> Result<Data, DynErr> {
+	let s = SPEAKER.get().ok_or("Speech synthesis is not configured")?;
+	let mut request = s.client.post(format!("{}/audio/speech", s.endpoint))
+		.json(&json!({ "model": s.model, "input": text, "voice": voice, "response_format": "mp3" }));
+	if !s.token.is_empty() {
+		request = request.bearer_auth(&s.token);
+	}
+	let audio = request.send().await?.error_for_status()?.bytes().await?;
+	Ok(Data::record(format!("base64://{}", base64::engine::general_purpose::STANDARD.encode(audio))))
+}
+
+/// `text` as a voice message, or `None` when it is too long or synthesis fails,
+/// so the caller sends it as text.
+pub async fn speak(text: &str, voice: &str) -> Option<Data> {
+	let s = SPEAKER.get()?;
+	if text.trim().is_empty() || text.chars().count() > s.max_chars {
+		return None;
+	}
+	match synthesize(text, voice).await {
+		Ok(record) => Some(record),
+		Err(e) => {
+			warn!("Speech synthesis failed, sending text: {:?}", e);
+			None
+		}
+	}
+}
+
+/// `~say <text>` speaks with the group's persona voice.
+pub struct SayPlugin;
+
+#[async_trait]
+impl Plugin for SayPlugin {
+	fn name(&self) -> &'static str {
+		"say"
+	}
+
+	fn commands(&self) -> Vec<&'static str> {
+		vec!["say"]
+	}
+
+	async fn on_load(&self, config: &Config, _db: Arc<Client>) -> Result<(), DynErr> {
+		init(&config.ai);
+		Ok(())
+	}
+
+	async fn on_command(&self, ctx: &MessageContext, cmd: &str, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+		if cmd != "say" {
+			return Ok(None);
+		}
+		let Some(s) = SPEAKER.get() else {
+			return Ok(Some(vec![Data::string("Speech synthesis is not configured".to_string())]));
+		};
+		if args.is_empty() {
+			return Ok(Some(vec![Data::string("Usage: ~say <text>".to_string())]));
+		}
+		let text = match moderation::check(ctx, moderation::Kind::Reply, &args.join(" ")).await? {
+			moderation::Verdict::Allow(text) => text,
+			moderation::Verdict::Refuse(refusal) => return Ok(Some(vec![Data::string(refusal)])),
+		};
+		let persona = persona::active(ctx.group_id.unwrap_or_default(), ctx.db.clone()).await?;
+		let voice = persona.voice.unwrap_or_else(|| s.voice.clone());
+		info!("[{} =>say] {}", ctx.msg_id, text);
+		Ok(Some(vec![speak(&text, &voice).await.unwrap_or(Data::string(text))]))
+	}
+}
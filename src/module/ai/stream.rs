@@ -0,0 +1,122 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use redis::{AsyncCommands, Client};
+
+use crate::constants::{AI_STREAM, AI_STREAM_INTERVAL_MS, AI_STREAM_MIN_CHARS};
+use crate::handler::DynErr;
+
+/// Whether replies in `gid` are streamed: the group's `~ai !stream` choice, else the config.
+pub async fn enabled(gid: u64, db: Arc<Client>) -> Result<bool, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let v: Option<bool> = conn.get(format!("ai:{}:stream", gid)).await?;
+	Ok(v.unwrap_or(*AI_STREAM.read().unwrap()))
+}
+
+pub async fn set_enabled(gid: u64, db: Arc<Client>, on: bool) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set(format!("ai:{}:stream", gid), on).await?;
+	Ok(())
+}
+
+/// Cuts a growing reply into chat-sized pieces at sentence ends.
+///
+/// The first complete sentence goes out as soon as it arrives; after that a piece
+/// needs `stream_min_chars` characters and `stream_interval_ms` since the last one,
+/// so a fast model doesn't flood the group.
+pub struct Chunker {
+	buf: String,
+	min_chars: usize,
+	interval: Duration,
+	last: Option<Instant>,
+}
+
+impl Default for Chunker {
+	fn default() -> Self {
+		Chunker {
+			buf: String::new(),
+			min_chars: *AI_STREAM_MIN_CHARS.read().unwrap(),
+			interval: Duration::from_millis(*AI_STREAM_INTERVAL_MS.read().unwrap()),
+			last: None,
+		}
+	}
+}
+
+impl Chunker {
+	/// Adds `text` and returns a piece if one is ready to send.
+	pub fn push(&mut self, text: &str) -> Option<String> {
+		self.buf += text;
+		let min_chars = match self.last {
+			None => 1,
+			Some(t) if t.elapsed() >= self.interval => self.min_chars,
+			Some(_) => return None,
+		};
+		let cut = last_boundary(&self.buf)?;
+		if self.buf[..cut].chars().count() < min_chars {
+			return None;
+		}
+		let rest = self.buf.split_off(cut);
+		let piece = std::mem::replace(&mut self.buf, rest);
+		self.last = Some(Instant::now());
+		let piece = piece.trim().to_string();
+		if piece.is_empty() { None } else { Some(piece) }
+	}
+
+	/// Whatever is left once the reply is complete.
+	pub fn finish(self) -> String {
+		self.buf.trim().to_string()
+	}
+}
+
+/// What `now`, the moderated reply so far, adds to `before`, as it was when the last
+/// piece went out. When masking reached back into text already sent, resumes where the two differ.
+pub fn unsent(before: &str, now: &str) -> String {
+	let cut = now.char_indices().zip(before.chars())
+		.find(|((_, a), b)| a != b)
+		.map(|((i, _), _)| i)
+		.unwrap_or_else(|| now.len().min(before.len()));
+	now[cut..].to_string()
+}
+
+/// Byte offset just past the last sentence or paragraph end in `s`.
+fn last_boundary(s: &str) -> Option<usize> {
+	let mut cut = None;
+	let mut chars = s.char_indices().peekable();
+	while let Some((i, c)) = chars.next() {
+		let end = match c {
+			'。' | '！' | '？' | '；' | '\n' => true,
+			// Only when followed by whitespace, so "3.14" and "e.g" stay whole.
+			'.' | '!' | '?' => chars.peek().is_some_and(|(_, n)| n.is_whitespace()),
+			_ => false,
+		};
+		if end {
+			cut = Some(i + c.len_utf8());
+		}
+	}
+	cut
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unsent_is_the_new_tail() {
+		assert_eq!(unsent("你好。", "你好。今天天气不错。"), "今天天气不错。");
+		assert_eq!(unsent("", "hello."), "hello.");
+		assert_eq!(unsent("hello.", "hello."), "");
+	}
+
+	#[test]
+	fn unsent_resumes_where_masking_reached_back() {
+		// "bad" went out before "word" arrived and the mask covered both.
+		assert_eq!(unsent("ok bad", "ok *** rest"), "*** rest");
+	}
+
+	#[test]
+	fn boundaries() {
+		assert_eq!(last_boundary("第一句。第二"), Some("第一句。".len()));
+		assert_eq!(last_boundary("pi is 3.14"), None);
+		assert_eq!(last_boundary("Done. Next"), Some("Done.".len()));
+	}
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::Datelike;
+use once_cell::sync::OnceCell;
+use redis::{AsyncCommands, Client};
+use serde_json::json;
+
+use super::backend::error::ErrorKind;
+use crate::handler::DynErr;
+use crate::plugin::MessageContext;
+
+/// How each message is framed for the AI. `{?name}...{/name}` is dropped when `name` is empty.
+pub const DEFAULT_MESSAGE: &str = "{nick}发送了以下内容：\n{?text}文字：{text}\n{/text}{?voice}语音：{voice}\n{/voice}{images}";
+
+/// Templates that can be set, besides the persona and init prompts, which take the same variables.
+pub const NAMES: [&str; 9] = ["message", "error.auth_expired", "error.rate_limited", "error.timeout", "error.circuit_open", "error.other",
+	"quota.group", "quota.user", "draw.quota"];
+
+pub const VARS: [&str; 13] = ["nick", "card", "role", "user_id", "group_id", "group_name", "time", "date", "weekday", "text", "images", "voice", "error"];
+
+const WEEKDAYS_ZH: [&str; 7] = ["星期一", "星期二", "星期三", "星期四", "星期五", "星期六", "星期日"];
+
+static CONFIG: OnceCell<(HashMap<String, String>, String)> = OnceCell::new();
+
+pub fn init(templates: &HashMap<String, String>, locale: &str) {
+	let _ = CONFIG.set((templates.clone(), locale.to_string()));
+}
+
+/// Whether built-in messages are in English rather than Chinese.
+pub fn english() -> bool {
+	CONFIG.get().is_some_and(|c| c.1 == "en")
+}
+
+/// The template for errors of `kind`.
+pub fn error_name(kind: ErrorKind) -> &'static str {
+	match kind {
+		ErrorKind::AuthExpired => "error.auth_expired",
+		ErrorKind::RateLimited => "error.rate_limited",
+		ErrorKind::Timeout => "error.timeout",
+		ErrorKind::CircuitOpen => "error.circuit_open",
+		_ => "error.other",
+	}
+}
+
+/// Fills `{name}` with `vars[name]`, keeps `{?name}...{/name}` only when `vars[name]`
+/// is non-empty, and turns `{{`/`}}` into braces. Unknown names are left as written.
+pub fn render(template: &str, vars: &HashMap<&str, String>) -> String {
+	let literal = |s: &str| s.replace("}}", "}");
+	let mut out = String::new();
+	let mut rest = template;
+	while let Some(i) = rest.find('{') {
+		out += &literal(&rest[..i]);
+		rest = &rest[i..];
+		if let Some(after) = rest.strip_prefix("{{") {
+			out.push('{');
+			rest = after;
+			continue;
+		}
+		let Some(end) = rest.find('}') else {
+			break;
+		};
+		let tag = &rest[1..end];
+		if let Some(name) = tag.strip_prefix('?') {
+			let close = format!("{{/{}}}", name);
+			if let Some(j) = rest[end + 1..].find(&close) {
+				if vars.get(name).is_some_and(|v| !v.is_empty()) {
+					out += &render(&rest[end + 1..end + 1 + j], vars);
+				}
+				rest = &rest[end + 1 + j + close.len()..];
+				continue;
+			}
+		}
+		match vars.get(tag) {
+			Some(v) => out += v,
+			None => out += &rest[..=end],
+		}
+		rest = &rest[end + 1..];
+	}
+	out + literal(rest).as_str()
+}
+
+/// The group's name, cached for an hour.
+async fn group_name(ctx: &MessageContext, gid: u64) -> Result<String, DynErr> {
+	let mut conn = ctx.db.get_multiplexed_async_connection().await?;
+	let key = format!("ai:{}:group_name", gid);
+	if let Some(name) = conn.get::<_, Option<String>>(&key).await? {
+		return Ok(name);
+	}
+	let info = ctx.call_api("get_group_info", json!({ "group_id": gid })).await?;
+	let name = info["group_name"].as_str().unwrap_or_default().to_string();
+	let _: () = conn.set_ex(&key, &name, 3600).await?;
+	Ok(name)
+}
+
+/// The variables describing `ctx`'s sender, chat and the current time.
+/// `text` is the message text; `images` and `voice` are left for the caller to fill.
+pub async fn vars(ctx: &MessageContext) -> HashMap<&'static str, String> {
+	let now = chrono::Local::now();
+	let zh = !english();
+	let card = ctx.sender.get("card").and_then(|c| c.as_str()).filter(|c| !c.is_empty()).unwrap_or(&ctx.nickname);
+	let mut vars = HashMap::from([
+		("nick", ctx.nickname.clone()),
+		("card", card.to_string()),
+		("role", ctx.sender.get("role").and_then(|r| r.as_str()).unwrap_or_default().to_string()),
+		("user_id", ctx.user_id.to_string()),
+		("group_id", ctx.group_id.map(|g| g.to_string()).unwrap_or_default()),
+		("group_name", String::new()),
+		("time", now.format("%H:%M").to_string()),
+		("date", now.format("%Y-%m-%d").to_string()),
+		("weekday", if zh { WEEKDAYS_ZH[now.weekday().num_days_from_monday() as usize].to_string() } else { now.format("%A").to_string() }),
+		("text", ctx.text.clone()),
+		("images", String::new()),
+		("voice", String::new()),
+	]);
+	if let Some(gid) = ctx.group_id {
+		// Outside a live event there is no connection to ask; the name stays empty.
+		if let Ok(name) = group_name(ctx, gid).await {
+			vars.insert("group_name", name);
+		}
+	}
+	vars
+}
+
+/// The group's own version of template `name`, else the configured one.
+pub async fn get(gid: u64, db: Arc<Client>, name: &str) -> Result<Option<String>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let own: Option<String> = conn.hget(format!("ai:{}:templates", gid), name).await?;
+	Ok(own.or_else(|| CONFIG.get().and_then(|c| c.0.get(name).cloned())))
+}
+
+/// Template `name` for the group, falling back to `default`.
+pub async fn get_or(gid: u64, db: Arc<Client>, name: &str, default: &str) -> Result<String, DynErr> {
+	Ok(get(gid, db, name).await?.unwrap_or_else(|| default.to_string()))
+}
+
+/// Sets the group's own template `name`, or says why it can't.
+pub async fn set(gid: u64, db: Arc<Client>, name: &str, template: &str) -> Result<Result<(), String>, DynErr> {
+	if !NAMES.contains(&name) {
+		return Ok(Err(format!("Unknown template {}, expected one of {}", name, NAMES.join(", "))));
+	}
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.hset(format!("ai:{}:templates", gid), name, template).await?;
+	Ok(Ok(()))
+}
+
+/// Drops the group's own template `name`, or says why it can't.
+pub async fn reset(gid: u64, db: Arc<Client>, name: &str) -> Result<Result<(), String>, DynErr> {
+	if !NAMES.contains(&name) {
+		return Ok(Err(format!("Unknown template {}, expected one of {}", name, NAMES.join(", "))));
+	}
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.hdel(format!("ai:{}:templates", gid), name).await?;
+	Ok(Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn vars(pairs: &[(&'static str, &str)]) -> HashMap<&'static str, String> {
+		pairs.iter().map(|(k, v)| (*k, v.to_string())).collect()
+	}
+
+	#[test]
+	fn fills_variables() {
+		let v = vars(&[("nick", "小明"), ("text", "hi")]);
+		assert_eq!(render("{nick}: {text}", &v), "小明: hi");
+	}
+
+	#[test]
+	fn keeps_unknown_names() {
+		assert_eq!(render("{nick} {unknown}", &vars(&[("nick", "a")])), "a {unknown}");
+	}
+
+	#[test]
+	fn sections_need_a_value() {
+		let t = "{?voice}语音：{voice}\n{/voice}end";
+		assert_eq!(render(t, &vars(&[("voice", "你好")])), "语音：你好\nend");
+		assert_eq!(render(t, &vars(&[("voice", "")])), "end");
+		assert_eq!(render(t, &vars(&[])), "end");
+	}
+
+	#[test]
+	fn escapes_braces() {
+		assert_eq!(render("{{nick}} {nick}", &vars(&[("nick", "a")])), "{nick} a");
+	}
+
+	#[test]
+	fn unclosed_brace_is_kept() {
+		assert_eq!(render("a {nick", &vars(&[("nick", "b")])), "a {nick");
+	}
+
+	#[test]
+	fn default_message_frames_text_and_voice() {
+		let v = vars(&[("nick", "n"), ("text", "t"), ("voice", ""), ("images", "")]);
+		assert_eq!(render(DEFAULT_MESSAGE, &v), "n发送了以下内容：\n文字：t\n");
+	}
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use redis::{AsyncCommands, Client};
+
+use crate::handler::DynErr;
+use crate::plugin::MessageContext;
+
+/// How long a bot message can be replied to and still continue its thread.
+const THREAD_TTL: i64 = 7 * 24 * 3600;
+
+/// Who shares a conversation in a group, set with `~ai !mode`.
+#[derive(PartialEq, Clone, Copy)]
+pub enum Mode {
+	/// Everyone in the group talks to the same conversation.
+	Shared,
+	/// Each member has their own conversation.
+	PerUser,
+	/// Every @ starts a conversation; replying to one of the bot's messages continues it.
+	PerThread,
+}
+
+impl Mode {
+	pub fn parse(s: &str) -> Option<Mode> {
+		match s {
+			"shared" => Some(Mode::Shared),
+			"per-user" => Some(Mode::PerUser),
+			"per-thread" => Some(Mode::PerThread),
+			_ => None,
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Mode::Shared => "shared",
+			Mode::PerUser => "per-user",
+			Mode::PerThread => "per-thread",
+		}
+	}
+}
+
+pub async fn mode(gid: u64, db: Arc<Client>) -> Result<Mode, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let v: Option<String> = conn.get(format!("ai:{}:mode", gid)).await?;
+	Ok(v.as_deref().and_then(Mode::parse).unwrap_or(Mode::Shared))
+}
+
+pub async fn set_mode(gid: u64, db: Arc<Client>, mode: Mode) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set(format!("ai:{}:mode", gid), mode.as_str()).await?;
+	Ok(())
+}
+
+/// The message `ctx` replies to, if any.
+pub(super) fn replied_to(ctx: &MessageContext) -> Option<u64> {
+	ctx.event["message"].as_array()?.iter()
+		.find(|s| s["type"] == "reply")
+		.and_then(|s| s["data"]["id"].as_str().and_then(|id| id.parse().ok()).or_else(|| s["data"]["id"].as_u64()))
+}
+
+/// The thread continued by replying to the bot's message `msg_id`.
+async fn thread_of(gid: u64, msg_id: u64, db: Arc<Client>) -> Result<Option<String>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	Ok(conn.get(format!("ai:{}:thread:{}", gid, msg_id)).await?)
+}
+
+/// Whether `ctx` replies to a message of a thread that is still open.
+pub async fn continues(ctx: &MessageContext) -> Result<bool, DynErr> {
+	let (Some(gid), Some(id)) = (ctx.group_id, replied_to(ctx)) else {
+		return Ok(false);
+	};
+	Ok(mode(gid, ctx.db.clone()).await? == Mode::PerThread && thread_of(gid, id, ctx.db.clone()).await?.is_some())
+}
+
+/// The conversation `ctx` belongs to, used as `{conv}` in the `ai:{conv}:{bot}:*` keys:
+/// `{gid}` when shared, `{gid}:u{user}` per user, `{gid}:t{first message}` per thread.
+/// Private chats are `0:u{user}`, so no one reads another's private turns.
+pub async fn conversation(ctx: &MessageContext) -> Result<String, DynErr> {
+	let Some(gid) = ctx.group_id else {
+		return Ok(format!("0:u{}", ctx.user_id));
+	};
+	Ok(match mode(gid, ctx.db.clone()).await? {
+		Mode::Shared => gid.to_string(),
+		Mode::PerUser => format!("{}:u{}", gid, ctx.user_id),
+		Mode::PerThread => match replied_to(ctx) {
+			Some(id) => thread_of(gid, id, ctx.db.clone()).await?.unwrap_or_else(|| format!("{}:t{}", gid, ctx.msg_id)),
+			None => format!("{}:t{}", gid, ctx.msg_id),
+		},
+	})
+}
+
+/// The group a conversation key belongs to.
+pub fn group_of(conv: &str) -> u64 {
+	conv.split(':').next().and_then(|g| g.parse().ok()).unwrap_or_default()
+}
+
+pub fn is_thread(conv: &str) -> bool {
+	conv.split(':').nth(1).is_some_and(|s| s.starts_with('t'))
+}
+
+/// Makes replies to the bot's message `msg_id` continue `conv`.
+pub async fn remember(conv: &str, msg_id: u64, db: Arc<Client>) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set_ex(format!("ai:{}:thread:{}", group_of(conv), msg_id), conv, THREAD_TTL as u64).await?;
+	Ok(())
+}
@@ -0,0 +1,447 @@
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use redis::{AsyncCommands, Client};
+use serde_json::{json, Value};
+use log::{info, warn};
+
+use super::backend::{AiBackend, ChatMessage, ChatOutcome, ChatRequest, ToolCall, ToolSpec};
+use super::history;
+use crate::constants::{AI_MAX_TOOL_ROUNDS, AI_TOOLS, AI_TOOL_COMMANDS};
+use crate::dto::Data;
+use crate::handler::DynErr;
+use crate::plugin::{self, MessageContext};
+
+const LOG_KEY: &str = "ai:tools:log";
+const LOG_LEN: isize = 200;
+
+/// Something the model may call during a conversation.
+#[async_trait]
+pub trait Tool: Send + Sync {
+	fn name(&self) -> &'static str;
+
+	/// Told to the model, so it knows when to call the tool.
+	fn description(&self) -> &'static str;
+
+	/// JSON schema of the arguments object.
+	fn parameters(&self) -> Value;
+
+	/// Whether the model may run this tool on behalf of `ctx`'s sender.
+	/// Tools that fail this check are neither offered nor run.
+	fn allowed(&self, _ctx: &MessageContext) -> bool {
+		true
+	}
+
+	/// Runs the tool. The returned text is handed back to the model.
+	async fn call(&self, ctx: &MessageContext, args: &Value) -> Result<String, DynErr>;
+}
+
+static TOOLS: Lazy<RwLock<Vec<Arc<dyn Tool>>>> = Lazy::new(|| RwLock::new(vec![
+	Arc::new(CurrentTime),
+	Arc::new(RollDice),
+	Arc::new(GroupMember),
+	Arc::new(SearchChatLog),
+	Arc::new(SetReminder),
+	Arc::new(RunCommand),
+	Arc::new(DrawImage),
+]));
+
+/// Adds `tool` to those offered to the model, replacing any tool of the same name.
+pub fn register(tool: Arc<dyn Tool>) {
+	let mut tools = TOOLS.write().unwrap();
+	tools.retain(|t| t.name() != tool.name());
+	tools.push(tool);
+}
+
+pub fn names() -> Vec<&'static str> {
+	TOOLS.read().unwrap().iter().map(|t| t.name()).collect()
+}
+
+fn find(name: &str) -> Option<Arc<dyn Tool>> {
+	TOOLS.read().unwrap().iter().find(|t| t.name() == name).cloned()
+}
+
+/// Specs of the tools `ctx`'s sender may use.
+pub fn specs(ctx: &MessageContext) -> Vec<ToolSpec> {
+	TOOLS.read().unwrap().iter()
+		.filter(|t| t.allowed(ctx))
+		.map(|t| ToolSpec { name: t.name(), description: t.description(), parameters: t.parameters() })
+		.collect()
+}
+
+/// Whether the model in `gid` may call tools: the group's `~ai !tools` choice, else the config.
+pub async fn enabled(gid: u64, db: Arc<Client>) -> Result<bool, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let v: Option<bool> = conn.get(format!("ai:{}:tools", gid)).await?;
+	Ok(v.unwrap_or(*AI_TOOLS.read().unwrap()))
+}
+
+pub async fn set_enabled(gid: u64, db: Arc<Client>, on: bool) -> Result<(), DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = conn.set(format!("ai:{}:tools", gid), on).await?;
+	Ok(())
+}
+
+/// Gets a reply to `req`, running the tools the model asks for in between.
+/// After `max_tool_rounds` rounds the model is told to answer with what it has.
+pub async fn chat(ctx: &MessageContext, backend: &dyn AiBackend, req: &ChatRequest) -> Result<String, DynErr> {
+	let specs = specs(ctx);
+	if specs.is_empty() {
+		return backend.chat(req).await;
+	}
+	let mut req = req.clone();
+	let rounds = *AI_MAX_TOOL_ROUNDS.read().unwrap();
+	for _ in 0..rounds {
+		let calls = match backend.chat_tools(&req, &specs).await? {
+			ChatOutcome::Reply(text) => return Ok(text),
+			ChatOutcome::ToolCalls(calls) => calls,
+		};
+		req.messages.push(ChatMessage::assistant_calls(calls.clone()));
+		for call in &calls {
+			let result = run(ctx, call).await;
+			req.messages.push(ChatMessage::tool(&call.id, &result));
+		}
+	}
+	req.system += "\n工具调用次数已用完，请根据已有的工具结果直接回答。";
+	match backend.chat_tools(&req, &specs).await? {
+		ChatOutcome::Reply(text) => Ok(text),
+		ChatOutcome::ToolCalls(_) => Err(format!("Still calling tools after {} rounds", rounds).into()),
+	}
+}
+
+/// Runs `call` for `ctx`'s sender and logs it. Failures are reported to the model, not raised.
+async fn run(ctx: &MessageContext, call: &ToolCall) -> String {
+	let result = match find(&call.name) {
+		None => Err(format!("Unknown tool {}", call.name).into()),
+		Some(t) if !t.allowed(ctx) => Err("Permission denied".into()),
+		Some(t) => t.call(ctx, &call.arguments).await,
+	};
+	let (ok, text) = match result {
+		Ok(text) => (true, text),
+		Err(e) => (false, format!("Error: {}", e)),
+	};
+	if ok {
+		info!("[{} =>tool] {}({}) -> {}", ctx.msg_id, call.name, call.arguments, text);
+	} else {
+		warn!("[{} =>tool] {}({}) -> {}", ctx.msg_id, call.name, call.arguments, text);
+	}
+	if let Err(e) = log(ctx, call, ok, &text).await {
+		warn!("[{} =>tool] failed to log call: {:?}", ctx.msg_id, e);
+	}
+	text
+}
+
+async fn log(ctx: &MessageContext, call: &ToolCall, ok: bool, result: &str) -> Result<(), DynErr> {
+	let entry = json!({
+		"ts": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+		"gid": ctx.group_id.unwrap_or_default(),
+		"user": ctx.user_id,
+		"tool": call.name,
+		"args": call.arguments,
+		"ok": ok,
+		"result": result.chars().take(200).collect::<String>(),
+	});
+	let mut conn = ctx.db.get_multiplexed_async_connection().await?;
+	let _: () = conn.lpush(LOG_KEY, entry.to_string()).await?;
+	let _: () = conn.ltrim(LOG_KEY, 0, LOG_LEN - 1).await?;
+	Ok(())
+}
+
+/// The newest `n` logged calls, newest first.
+pub async fn recent(db: Arc<Client>, n: usize) -> Result<Vec<Value>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let raw: Vec<String> = conn.lrange(LOG_KEY, 0, n as isize - 1).await?;
+	Ok(raw.iter().filter_map(|r| serde_json::from_str(r).ok()).collect())
+}
+
+struct CurrentTime;
+
+#[async_trait]
+impl Tool for CurrentTime {
+	fn name(&self) -> &'static str {
+		"current_time"
+	}
+
+	fn description(&self) -> &'static str {
+		"获取当前的日期、时间和星期。"
+	}
+
+	fn parameters(&self) -> Value {
+		json!({ "type": "object", "properties": {} })
+	}
+
+	async fn call(&self, _ctx: &MessageContext, _args: &Value) -> Result<String, DynErr> {
+		Ok(chrono::Local::now().format("%Y-%m-%d %H:%M:%S %A (UTC%:z)").to_string())
+	}
+}
+
+struct RollDice;
+
+#[async_trait]
+impl Tool for RollDice {
+	fn name(&self) -> &'static str {
+		"roll_dice"
+	}
+
+	fn description(&self) -> &'static str {
+		"掷骰子，返回每个骰子的点数和总和。"
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"sides": { "type": "integer", "minimum": 2, "maximum": 1000, "description": "每个骰子的面数，默认 6" },
+				"count": { "type": "integer", "minimum": 1, "maximum": 20, "description": "骰子个数，默认 1" },
+			},
+		})
+	}
+
+	async fn call(&self, _ctx: &MessageContext, args: &Value) -> Result<String, DynErr> {
+		let sides = args["sides"].as_u64().unwrap_or(6).clamp(2, 1000);
+		let count = args["count"].as_u64().unwrap_or(1).clamp(1, 20);
+		let mut rng = rand::rng();
+		let rolls: Vec<u64> = (0..count).map(|_| rng.random_range(1..=sides)).collect();
+		Ok(format!("{}d{}: {:?}, total {}", count, sides, rolls, rolls.iter().sum::<u64>()))
+	}
+}
+
+struct DrawImage;
+
+#[async_trait]
+impl Tool for DrawImage {
+	fn name(&self) -> &'static str {
+		"draw_image"
+	}
+
+	fn description(&self) -> &'static str {
+		"根据描述生成一张图片并直接发到聊天中。描述要具体，说明主体、风格和构图。"
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"prompt": { "type": "string", "description": "图片描述" },
+			},
+			"required": ["prompt"],
+		})
+	}
+
+	fn allowed(&self, _ctx: &MessageContext) -> bool {
+		super::draw::enabled()
+	}
+
+	async fn call(&self, ctx: &MessageContext, args: &Value) -> Result<String, DynErr> {
+		let prompt = args["prompt"].as_str().filter(|p| !p.is_empty()).ok_or("prompt required")?;
+		match super::draw::draw(ctx, prompt).await? {
+			Ok(image) => {
+				ctx.send(vec![image]).await?;
+				Ok("The image was sent to the chat".to_string())
+			}
+			Err(refusal) => Ok(format!("Not drawn: {}", refusal)),
+		}
+	}
+}
+
+struct GroupMember;
+
+fn member_card(m: &Value) -> Value {
+	json!({
+		"user_id": m["user_id"],
+		"nickname": m["nickname"],
+		"card": m["card"],
+		"title": m["title"],
+		"role": m["role"],
+		"level": m["level"],
+		"join_time": m["join_time"],
+		"last_sent_time": m["last_sent_time"],
+	})
+}
+
+#[async_trait]
+impl Tool for GroupMember {
+	fn name(&self) -> &'static str {
+		"group_member"
+	}
+
+	fn description(&self) -> &'static str {
+		"查询本群成员的资料（群名片、头衔、身份、等级、入群时间等）。按 QQ 号或昵称/群名片查找。"
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"user_id": { "type": "integer", "description": "成员 QQ 号" },
+				"name": { "type": "string", "description": "昵称或群名片中包含的文字" },
+			},
+		})
+	}
+
+	fn allowed(&self, ctx: &MessageContext) -> bool {
+		ctx.group_id.is_some() && ctx.outbox.is_some()
+	}
+
+	async fn call(&self, ctx: &MessageContext, args: &Value) -> Result<String, DynErr> {
+		let gid = ctx.group_id.ok_or("Not in a group")?;
+		if let Some(uid) = args["user_id"].as_u64() {
+			let m = ctx.call_api("get_group_member_info", json!({ "group_id": gid, "user_id": uid })).await?;
+			return Ok(member_card(&m).to_string());
+		}
+		let name = args["name"].as_str().filter(|n| !n.is_empty()).ok_or("user_id or name required")?;
+		let members = ctx.call_api("get_group_member_list", json!({ "group_id": gid })).await?;
+		let found: Vec<Value> = members.as_array().into_iter().flatten()
+			.filter(|m| [&m["card"], &m["nickname"]].iter().any(|s| s.as_str().is_some_and(|s| s.contains(name))))
+			.take(5)
+			.map(member_card)
+			.collect();
+		if found.is_empty() {
+			return Ok(format!("No member matches {}", name));
+		}
+		Ok(Value::Array(found).to_string())
+	}
+}
+
+struct SearchChatLog;
+
+#[async_trait]
+impl Tool for SearchChatLog {
+	fn name(&self) -> &'static str {
+		"search_chat_log"
+	}
+
+	fn description(&self) -> &'static str {
+		"在本会话的聊天记录中搜索包含关键词的消息，返回最新的若干条。"
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"keyword": { "type": "string" },
+				"limit": { "type": "integer", "minimum": 1, "maximum": 20, "description": "最多返回几条，默认 5" },
+			},
+			"required": ["keyword"],
+		})
+	}
+
+	async fn call(&self, ctx: &MessageContext, args: &Value) -> Result<String, DynErr> {
+		let keyword = args["keyword"].as_str().filter(|k| !k.is_empty()).ok_or("keyword required")?;
+		let limit = args["limit"].as_u64().unwrap_or(5).clamp(1, 20) as usize;
+		let gid = ctx.group_id.unwrap_or_default();
+		let (_, _, model) = super::setup(gid, ctx.db.clone()).await?;
+		let conv = super::thread::conversation(ctx).await?;
+		let turns = history::load(ctx.db.clone(), &history::key(&conv, &super::bot_uid(&model))).await?;
+		let hits: Vec<String> = turns.iter().rev()
+			.filter(|t| t.content.contains(keyword))
+			.take(limit)
+			.map(|t| {
+				let when = chrono::DateTime::from_timestamp(t.ts as i64, 0)
+					.map(|d| d.with_timezone(&chrono::Local).format("%m-%d %H:%M").to_string())
+					.unwrap_or_default();
+				format!("[{} {}] {}", when, t.speaker, t.content.chars().take(200).collect::<String>())
+			})
+			.collect();
+		if hits.is_empty() {
+			return Ok(format!("No message contains {}", keyword));
+		}
+		Ok(hits.join("\n"))
+	}
+}
+
+struct SetReminder;
+
+#[async_trait]
+impl Tool for SetReminder {
+	fn name(&self) -> &'static str {
+		"set_reminder"
+	}
+
+	fn description(&self) -> &'static str {
+		"在若干分钟后在当前会话中提醒用户。提醒只保存在内存中，机器人重启后会丢失。"
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"minutes": { "type": "integer", "minimum": 1, "maximum": 10080 },
+				"text": { "type": "string", "description": "提醒内容" },
+			},
+			"required": ["minutes", "text"],
+		})
+	}
+
+	fn allowed(&self, ctx: &MessageContext) -> bool {
+		ctx.outbox.is_some()
+	}
+
+	async fn call(&self, ctx: &MessageContext, args: &Value) -> Result<String, DynErr> {
+		let minutes = args["minutes"].as_u64().ok_or("minutes required")?.clamp(1, 10080);
+		let text = args["text"].as_str().filter(|t| !t.is_empty()).ok_or("text required")?.to_string();
+		let outbox = ctx.outbox.clone().ok_or("No connection to send on")?;
+		let (group_id, user_id, event, db) = (ctx.group_id, ctx.user_id, ctx.event.clone(), ctx.db.clone());
+		let mut message = vec![Data::string(format!("提醒：{}", text))];
+		if group_id.is_some() {
+			message.insert(0, Data::at(user_id));
+		}
+		tokio::spawn(async move {
+			tokio::time::sleep(std::time::Duration::from_secs(minutes * 60)).await;
+			let mut resp = crate::handler::reply_to(group_id, user_id, message);
+			if let Err(e) = crate::middleware::post(&event, &mut resp, db).await {
+				warn!("[reminder {}] {:?}", user_id, e);
+				return;
+			}
+			if let Err(e) = crate::handler::send(resp, outbox).await {
+				warn!("[reminder {}] {:?}", user_id, e);
+			}
+		});
+		Ok(format!("Reminder set for {} minute(s) from now", minutes))
+	}
+}
+
+struct RunCommand;
+
+#[async_trait]
+impl Tool for RunCommand {
+	fn name(&self) -> &'static str {
+		"run_command"
+	}
+
+	fn description(&self) -> &'static str {
+		"以当前用户的身份运行一条机器人命令并返回输出。"
+	}
+
+	fn parameters(&self) -> Value {
+		let commands = AI_TOOL_COMMANDS.read().unwrap().clone();
+		json!({
+			"type": "object",
+			"properties": {
+				"command": { "type": "string", "enum": commands, "description": "命令名，不带 ~" },
+				"args": { "type": "string", "description": "空格分隔的参数" },
+			},
+			"required": ["command"],
+		})
+	}
+
+	fn allowed(&self, _ctx: &MessageContext) -> bool {
+		!AI_TOOL_COMMANDS.read().unwrap().is_empty()
+	}
+
+	async fn call(&self, ctx: &MessageContext, args: &Value) -> Result<String, DynErr> {
+		let command = args["command"].as_str().unwrap_or_default().trim_start_matches('~');
+		// `ai` would start a conversation inside this one.
+		if command == "ai" || !AI_TOOL_COMMANDS.read().unwrap().iter().any(|c| c == command) {
+			return Err(format!("Command {} is not allowed", command).into());
+		}
+		let cmd_args: Vec<&str> = args["args"].as_str().unwrap_or_default().split_whitespace().collect();
+		// Plugins check the sender's identity themselves, so the command runs with the user's rights.
+		let out = plugin::registry().command(ctx, command, &cmd_args).await?;
+		let text: Vec<&str> = out.iter().filter_map(|d| d.data["text"].as_str()).collect();
+		Ok(text.join(""))
+	}
+}
@@ -0,0 +1,151 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use log::{info, warn};
+
+use super::history::{self, Turn};
+use super::{memory, setup, share, thread};
+use crate::dto::Data;
+use crate::handler::DynErr;
+use crate::plugin::MessageContext;
+
+/// Largest dump `~ai !import` reads from a file.
+const MAX_IMPORT_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Characters of the newest turns carried over as a summary when the backend
+/// keeps its own history and the dump has no summary.
+const CARRY_CHARS: usize = 1500;
+
+/// A conversation as written by `~ai !export json` and read by `~ai !import`.
+#[derive(Serialize, Deserialize)]
+pub struct Dump {
+	pub version: u32,
+	pub conv: String,
+	pub backend: String,
+	pub model: String,
+	/// Unix seconds.
+	pub exported_at: u64,
+	#[serde(default)]
+	pub memory: Option<String>,
+	pub turns: Vec<Turn>,
+}
+
+fn time(ts: u64) -> String {
+	chrono::DateTime::from_timestamp(ts as i64, 0)
+		.map(|d| d.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+		.unwrap_or_default()
+}
+
+impl Dump {
+	pub fn markdown(&self) -> String {
+		let mut out = format!("# Conversation {}\n\n{} / {}, exported {}\n", self.conv, self.backend, self.model, time(self.exported_at));
+		if let Some(m) = &self.memory {
+			out += &format!("\n> Summary of earlier turns: {}\n", m);
+		}
+		for t in &self.turns {
+			out += &format!("\n**{}** ({})\n\n{}\n", t.speaker, time(t.ts), t.content);
+		}
+		out
+	}
+}
+
+/// The conversation `ctx` is in, as a dump.
+pub async fn dump(ctx: &MessageContext) -> Result<Dump, DynErr> {
+	let gid = ctx.group_id.unwrap_or_default();
+	let (backend, _, model) = setup(gid, ctx.db.clone()).await?;
+	let conv = thread::conversation(ctx).await?;
+	let bot = super::bot_uid(&model);
+	Ok(Dump {
+		version: 1,
+		backend: backend.name().to_string(),
+		exported_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+		memory: memory::get(ctx.db.clone(), &conv, &bot).await?,
+		turns: history::load(ctx.db.clone(), &history::key(&conv, &bot)).await?,
+		conv,
+		model,
+	})
+}
+
+/// `~ai !export [md|json]` sends the current conversation back as a file, or as a
+/// forward message when OneBot can't take the file.
+pub async fn export_command(ctx: &MessageContext, args: &[&str]) -> Result<Vec<Data>, DynErr> {
+	let format = args.first().copied().unwrap_or("md");
+	if !matches!(format, "md" | "json") {
+		return Ok(vec![Data::string("Usage: ~ai !export [md|json]".to_string())]);
+	}
+	let dump = dump(ctx).await?;
+	if dump.turns.is_empty() {
+		return Ok(vec![Data::string("History is empty".to_string())]);
+	}
+	let name = format!("ai-{}-{}.{}", dump.conv.replace(':', "-"), chrono::Local::now().format("%Y%m%d%H%M"), format);
+	let content = if format == "json" { serde_json::to_string_pretty(&dump)? } else { dump.markdown() };
+	if let Err(e) = share::upload(ctx, &name, &content).await {
+		warn!("[{}] upload of {} failed, sending a forward message: {:?}", ctx.msg_id, name, e);
+		let nodes: Vec<(String, String)> = if format == "json" {
+			vec![(name.clone(), content)]
+		} else {
+			dump.turns.iter().map(|t| (t.speaker.clone(), t.content.clone())).collect()
+		};
+		share::forward(ctx, &nodes).await?;
+	}
+	info!("[{}] exported {} turn(s) of {} as {}", ctx.msg_id, dump.turns.len(), dump.conv, format);
+	Ok(vec![])
+}
+
+/// The newest turns as plain text, for a backend that can't be sent history.
+fn carry_over(turns: &[Turn]) -> String {
+	let mut lines: Vec<String> = Vec::new();
+	let mut len = 0;
+	for t in turns.iter().rev() {
+		let line = format!("{}：{}", t.speaker, t.content);
+		len += line.chars().count();
+		if len > CARRY_CHARS && !lines.is_empty() {
+			break;
+		}
+		lines.push(line);
+	}
+	lines.reverse();
+	lines.join("\n")
+}
+
+/// `~ai !import [json]` replaces the current conversation with a dump, given inline
+/// or as the JSON file the command replies to.
+pub async fn import_command(ctx: &MessageContext) -> Result<Vec<Data>, DynErr> {
+	let inline = ctx.text.split_once("!import").map(|(_, rest)| rest.trim()).unwrap_or_default();
+	let raw = if !inline.is_empty() {
+		inline.to_string()
+	} else if let Some((_, text)) = share::replied_text(ctx, MAX_IMPORT_BYTES).await? {
+		text
+	} else {
+		return Ok(vec![Data::string("Usage: ~ai !import <json>, or reply to an exported JSON file with ~ai !import".to_string())]);
+	};
+	let dump: Dump = match serde_json::from_str(&raw) {
+		Ok(dump) => dump,
+		Err(e) => return Ok(vec![Data::string(format!("Not a conversation dump: {}", e))]),
+	};
+	if let Some(t) = dump.turns.iter().find(|t| !matches!(t.role.as_str(), "user" | "assistant")) {
+		return Ok(vec![Data::string(format!("Not a conversation dump: turn role {} is neither user nor assistant", t.role))]);
+	}
+
+	let gid = ctx.group_id.unwrap_or_default();
+	let (backend, _, model) = setup(gid, ctx.db.clone()).await?;
+	let conv = thread::conversation(ctx).await?;
+	let bot = super::bot_uid(&model);
+	super::clear_record(&conv, ctx.db.clone(), "main").await?;
+	history::push(ctx.db.clone(), &history::key(&conv, &bot), &dump.turns).await?;
+
+	// Monica keeps the history on its side and is only sent the newest message,
+	// so the imported turns reach it through the summary.
+	let summary = match (&dump.memory, backend.name()) {
+		(Some(m), _) => Some(m.clone()),
+		(None, "monica") => Some(carry_over(&dump.turns)),
+		_ => None,
+	};
+	if let Some(summary) = summary.filter(|s| !s.is_empty()) {
+		let mut conn = ctx.db.get_multiplexed_async_connection().await?;
+		let _: () = conn.set(memory::key(&conv, &bot), summary).await?;
+	}
+	info!("[{}] imported {} turn(s) from {} into {}", ctx.msg_id, dump.turns.len(), dump.conv, conv);
+	Ok(vec![Data::string(format!("Imported {} turn(s) from {} ({} / {})", dump.turns.len(), dump.conv, dump.backend, dump.model))])
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use redis::{AsyncCommands, Client};
+use log::error;
+
+use super::backend::{AiBackend, ChatOutcome, ChatRequest, ChatStream, ToolSpec};
+use super::backend::vision::{VisionBackend, VisionRequest};
+use super::history::estimate_tokens;
+use super::{template, thread};
+use crate::constants::{AI_QUOTA_GROUP_DAILY, AI_QUOTA_USER_DAILY, OWNER_ID};
+use crate::handler::DynErr;
+use crate::plugin::MessageContext;
+
+/// Days a daily usage hash is kept.
+const KEEP_DAYS: i64 = 90;
+
+/// Estimated prompt tokens of one image, what OpenAI charges for a 1024px one in detail.
+const IMAGE_TOKENS: u64 = 765;
+
+/// `YYYYMMDD` in local time, the suffix of `ai:usage:{day}`.
+pub fn today() -> String {
+	chrono::Local::now().format("%Y%m%d").to_string()
+}
+
+fn key(day: &str) -> String {
+	format!("ai:usage:{}", day)
+}
+
+/// Estimated tokens of everything `req` sends.
+fn prompt_tokens(req: &ChatRequest) -> u64 {
+	let mut n = estimate_tokens(&req.system);
+	for m in &req.messages {
+		n += estimate_tokens(&m.content);
+		for c in &m.tool_calls {
+			n += estimate_tokens(&c.name) + estimate_tokens(&c.arguments.to_string());
+		}
+	}
+	n as u64
+}
+
+/// Adds one request to today's counters of its group, user and model.
+pub async fn record(req: &ChatRequest, prompt: u64, reply: u64) -> Result<(), DynErr> {
+	count(req.db.clone(), thread::group_of(&req.conv), req.user, &req.model, prompt, reply).await
+}
+
+/// Adds one request of `user` in `gid` (0 in private) to `model` to today's counters.
+///
+/// Fields of `ai:usage:{day}` are `{group|user|model}:{id}:{in|out|req}`, where
+/// `in` and `out` are estimated prompt and reply tokens.
+pub async fn count(db: Arc<Client>, gid: u64, user: u64, model: &str, prompt: u64, reply: u64) -> Result<(), DynErr> {
+	let key = key(&today());
+	let mut pipe = redis::pipe();
+	let ids = [
+		format!("group:{}", gid),
+		format!("user:{}", user),
+		format!("model:{}", model),
+	];
+	for id in &ids {
+		pipe.hincr(&key, format!("{}:in", id), prompt).ignore()
+			.hincr(&key, format!("{}:out", id), reply).ignore()
+			.hincr(&key, format!("{}:req", id), 1).ignore();
+	}
+	pipe.expire(&key, KEEP_DAYS * 24 * 3600).ignore();
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let _: () = pipe.query_async(&mut conn).await?;
+	Ok(())
+}
+
+/// Adds `n` generated images (-1 takes one back) to today's `{group|user|model}:{id}:img`
+/// counters, in one transaction. Returns the user's count after the change.
+pub async fn record_image(gid: u64, user: u64, model: &str, db: Arc<Client>, n: i64) -> Result<u64, DynErr> {
+	let key = key(&today());
+	let mut pipe = redis::pipe();
+	pipe.atomic()
+		.hincr(&key, format!("user:{}:img", user), n)
+		.hincr(&key, format!("group:{}:img", gid), n).ignore()
+		.hincr(&key, format!("model:{}:img", model), n).ignore()
+		.expire(&key, KEEP_DAYS * 24 * 3600).ignore();
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	let (used,): (i64,) = pipe.query_async(&mut conn).await?;
+	Ok(used.max(0) as u64)
+}
+
+/// Every counter of `day`.
+pub async fn day(db: Arc<Client>, day: &str) -> Result<HashMap<String, u64>, DynErr> {
+	let mut conn = db.get_multiplexed_async_connection().await?;
+	Ok(conn.hgetall(key(day)).await?)
+}
+
+/// Tokens in and out used by `id` (e.g. `group:123`) according to `counters`.
+pub fn tokens(counters: &HashMap<String, u64>, id: &str) -> u64 {
+	counters.get(&format!("{}:in", id)).copied().unwrap_or_default()
+		+ counters.get(&format!("{}:out", id)).copied().unwrap_or_default()
+}
+
+/// Whose daily quota ran out.
+#[derive(Clone, Copy)]
+pub enum Exceeded {
+	Group,
+	User,
+}
+
+impl Exceeded {
+	/// The template users are refused with.
+	pub fn template(&self) -> &'static str {
+		match self {
+			Exceeded::Group => "quota.group",
+			Exceeded::User => "quota.user",
+		}
+	}
+}
+
+/// Whether the group or the user has used up today's quota.
+pub async fn over_quota(gid: u64, user: u64, db: Arc<Client>) -> Result<Option<Exceeded>, DynErr> {
+	let group_quota = *AI_QUOTA_GROUP_DAILY.read().unwrap();
+	let user_quota = *AI_QUOTA_USER_DAILY.read().unwrap();
+	if group_quota == 0 && user_quota == 0 {
+		return Ok(None);
+	}
+	let counters = day(db, &today()).await?;
+	if gid != 0 && group_quota > 0 && tokens(&counters, &format!("group:{}", gid)) >= group_quota {
+		return Ok(Some(Exceeded::Group));
+	}
+	if user != *OWNER_ID.read().unwrap() && user_quota > 0 && tokens(&counters, &format!("user:{}", user)) >= user_quota {
+		return Ok(Some(Exceeded::User));
+	}
+	Ok(None)
+}
+
+/// What users are told when a quota ran out, unless a template replaces it.
+pub fn message(which: Exceeded) -> &'static str {
+	match (which, template::english()) {
+		(Exceeded::Group, false) => "本群今天的 AI 额度已经用完了，明天再来找我聊吧",
+		(Exceeded::Group, true) => "This group has used up today's AI quota. Talk to you tomorrow!",
+		(Exceeded::User, false) => "你今天的 AI 额度已经用完了，明天再来找我聊吧",
+		(Exceeded::User, true) => "You have used up today's AI quota. Talk to you tomorrow!",
+	}
+}
+
+/// The refusal `ctx`'s sender gets when `which` quota ran out.
+pub async fn refusal(ctx: &MessageContext, which: Exceeded) -> Result<String, DynErr> {
+	let gid = ctx.group_id.unwrap_or_default();
+	let t = template::get_or(gid, ctx.db.clone(), which.template(), message(which)).await?;
+	Ok(template::render(&t, &template::vars(ctx).await))
+}
+
+/// Counts the usage of every request that goes through the wrapped backend,
+/// chat and vision alike.
+pub struct Metered<B: ?Sized>(pub Arc<B>);
+
+#[async_trait]
+impl<B: AiBackend + ?Sized> AiBackend for Metered<B> {
+	fn name(&self) -> &'static str {
+		self.0.name()
+	}
+
+	fn default_model(&self) -> String {
+		self.0.default_model()
+	}
+
+	async fn chat_stream(&self, req: &ChatRequest) -> Result<ChatStream, DynErr> {
+		let mut upstream = self.0.chat_stream(req).await?;
+		let prompt = prompt_tokens(req);
+		let req = req.clone();
+		let (tx, rx) = mpsc::unbounded();
+		tokio::spawn(async move {
+			let mut reply = 0;
+			while let Some(chunk) = upstream.next().await {
+				if let Ok(text) = &chunk {
+					reply += estimate_tokens(text) as u64;
+				}
+				if tx.unbounded_send(chunk).is_err() {
+					break;
+				}
+			}
+			if let Err(e) = record(&req, prompt, reply).await {
+				error!("[{} {}] failed to record usage: {:?}", req.conv, req.model, e);
+			}
+		});
+		Ok(rx.boxed())
+	}
+
+	async fn chat_tools(&self, req: &ChatRequest, tools: &[ToolSpec]) -> Result<ChatOutcome, DynErr> {
+		let outcome = self.0.chat_tools(req, tools).await?;
+		let reply = match &outcome {
+			ChatOutcome::Reply(text) => estimate_tokens(text),
+			ChatOutcome::ToolCalls(calls) => calls.iter().map(|c| estimate_tokens(&c.arguments.to_string()) + 4).sum(),
+		};
+		let tools_len: usize = tools.iter().map(|t| estimate_tokens(t.description) + estimate_tokens(&t.parameters.to_string())).sum();
+		if let Err(e) = record(req, prompt_tokens(req) + tools_len as u64, reply as u64).await {
+			error!("[{} {}] failed to record usage: {:?}", req.conv, req.model, e);
+		}
+		Ok(outcome)
+	}
+}
+
+#[async_trait]
+impl<B: VisionBackend + ?Sized> VisionBackend for Metered<B> {
+	fn name(&self) -> &'static str {
+		self.0.name()
+	}
+
+	fn model(&self) -> String {
+		self.0.model()
+	}
+
+	async fn describe(&self, req: &VisionRequest<'_>) -> Result<String, DynErr> {
+		let text = self.0.describe(req).await?;
+		let model = self.0.model();
+		let prompt = estimate_tokens(req.prompt) as u64 + IMAGE_TOKENS;
+		if let Err(e) = count(req.db.clone(), req.gid, req.user, &model, prompt, estimate_tokens(&text) as u64).await {
+			error!("[{} {}] failed to record usage: {:?}", req.gid, model, e);
+		}
+		Ok(text)
+	}
+}
@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use reqwest::header::CONTENT_TYPE;
+use serde_json::json;
+use uuid::Uuid;
+use log::info;
+
+use crate::config;
+use crate::dto::{Data, RecordData};
+use crate::handler::DynErr;
+use crate::module::ai_img::download_at_most;
+use crate::plugin::MessageContext;
+
+/// A Whisper-compatible `/audio/transcriptions` API.
+struct Transcriber {
+	endpoint: String,
+	token: String,
+	model: String,
+	language: Option<String>,
+	echo: bool,
+	max_bytes: usize,
+	client: reqwest::Client,
+}
+
+static TRANSCRIBER: OnceCell<Transcriber> = OnceCell::new();
+
+pub fn init(config: &config::Ai) {
+	let v = &config.voice;
+	let endpoint = v.endpoint.clone().or_else(|| config.openai.as_ref().map(|o| o.endpoint.clone()));
+	let Some(endpoint) = endpoint else {
+		return;
+	};
+	let token = v.token.clone().or_else(|| config.openai.as_ref().map(|o| o.token.clone())).unwrap_or_default();
+	let _ = TRANSCRIBER.set(Transcriber {
+		endpoint: endpoint.trim_end_matches('/').to_string(),
+		token,
+		model: v.model.clone(),
+		language: v.language.clone(),
+		echo: v.echo,
+		max_bytes: v.max_kb as usize * 1024,
+		client: reqwest::Client::builder().timeout(Duration::from_secs(60)).build().unwrap_or_default(),
+	});
+}
+
+pub fn enabled() -> bool {
+	TRANSCRIBER.get().is_some()
+}
+
+/// Whether transcripts are also posted to the group.
+pub fn echoes() -> bool {
+	TRANSCRIBER.get().is_some_and(|t| t.echo)
+}
+
+/// The container of `bytes` if the transcription API takes it as is.
+/// QQ's own SILK and AMR recordings are not among them.
+fn format(bytes: &[u8]) -> Option<&'static str> {
+	match bytes {
+		[b'R', b'I', b'F', b'F', ..] => Some("wav"),
+		[b'I', b'D', b'3', ..] => Some("mp3"),
+		[0xff, b, ..] if b & 0xe0 == 0xe0 => Some("mp3"),
+		[b'O', b'g', b'g', b'S', ..] => Some("ogg"),
+		[b'f', b'L', b'a', b'C', ..] => Some("flac"),
+		_ => None,
+	}
+}
+
+impl Transcriber {
+	/// The recording in a format the API takes, with its file extension.
+	///
+	/// We have no SILK decoder, so other formats are converted by OneBot's
+	/// `get_record`, which runs ffmpeg on its side. Nothing over `max_bytes` is read.
+	async fn audio(&self, ctx: &MessageContext, rec: &RecordData) -> Result<(Vec<u8>, &'static str), DynErr> {
+		if !rec.url.is_empty() {
+			let bytes = download_at_most(&self.client, &rec.url, self.max_bytes).await?;
+			if let Some(ext) = format(&bytes) {
+				return Ok((bytes, ext));
+			}
+		}
+		let data = ctx.call_api("get_record", json!({ "file": rec.file, "out_format": "mp3" })).await?;
+		let too_large = || format!("Voice message {} is larger than {} KiB", rec.file, self.max_bytes / 1024).into();
+		let bytes = if let Some(b64) = data["base64"].as_str() {
+			// Every 4 base64 characters hold 3 bytes; checked before decoding any of them.
+			if b64.len() / 4 * 3 > self.max_bytes {
+				return Err(too_large());
+			}
+			base64::engine::general_purpose::STANDARD.decode(b64)?
+		} else if let Some(url) = data["url"].as_str().filter(|u| u.starts_with("http")) {
+			download_at_most(&self.client, url, self.max_bytes).await?
+		} else if let Some(path) = data["file"].as_str() {
+			// A path on OneBot's disk, readable when it runs on this machine.
+			if tokio::fs::metadata(path).await?.len() > self.max_bytes as u64 {
+				return Err(too_large());
+			}
+			tokio::fs::read(path).await?
+		} else {
+			return Err(format!("get_record returned no audio for {}", rec.file).into());
+		};
+		Ok((bytes, "mp3"))
+	}
+
+	async fn transcribe(&self, audio: Vec<u8>, ext: &str) -> Result<String, DynErr> {
+		let boundary = format!("----ruast{}", Uuid::new_v4().simple());
+		let mut body = Vec::with_capacity(audio.len() + 512);
+		let mut fields = vec![("model", self.model.as_str()), ("response_format", "json")];
+		if let Some(language) = &self.language {
+			fields.push(("language", language.as_str()));
+		}
+		for (name, value) in fields {
+			body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", boundary, name, value).as_bytes());
+		}
+		body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"voice.{}\"\r\nContent-Type: application/octet-stream\r\n\r\n", boundary, ext).as_bytes());
+		body.extend(audio);
+		body.extend(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+		let mut request = self.client.post(format!("{}/audio/transcriptions", self.endpoint))
+			.header(CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+			.body(body);
+		if !self.token.is_empty() {
+			request = request.bearer_auth(&self.token);
+		}
+		let v: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+		Ok(v["text"].as_str().unwrap_or_default().trim().to_string())
+	}
+}
+
+/// What was said in `ctx`'s voice messages, empty when it has none or
+/// transcription is off. Posted to the chat too when `echo` is set.
+pub async fn heard(ctx: &MessageContext) -> Result<String, DynErr> {
+	let Some(t) = TRANSCRIBER.get() else {
+		return Ok(String::new());
+	};
+	let mut said = Vec::new();
+	for rec in &ctx.records {
+		let (audio, ext) = t.audio(ctx, rec).await?;
+		if audio.len() > t.max_bytes {
+			return Err(format!("Voice message {} is larger than {} KiB", rec.file, t.max_bytes / 1024).into());
+		}
+		let text = t.transcribe(audio, ext).await?;
+		if !text.is_empty() {
+			said.push(text);
+		}
+	}
+	let said = said.join("\n");
+	if !said.is_empty() {
+		info!("[{} =>voice] {}", ctx.msg_id, said);
+		if t.echo {
+			ctx.send(vec![Data::reply(ctx.msg_id), Data::string(format!("🎤 {}", said))]).await?;
+		}
+	}
+	Ok(said)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn formats_the_api_takes() {
+		assert_eq!(format(b"RIFF\0\0\0\0WAVEfmt "), Some("wav"));
+		assert_eq!(format(b"ID3\x04\0"), Some("mp3"));
+		assert_eq!(format(&[0xff, 0xfb, 0x90, 0x00]), Some("mp3"));
+		assert_eq!(format(b"OggS\0\x02"), Some("ogg"));
+		assert_eq!(format(b"fLaC\0\0\0\x22"), Some("flac"));
+	}
+
+	#[test]
+	fn qq_recordings_need_converting() {
+		assert_eq!(format(b"\x02#!SILK_V3"), None);
+		assert_eq!(format(b"#!AMR\n"), None);
+		assert_eq!(format(&[0xff, 0x10]), None);
+		assert_eq!(format(b""), None);
+	}
+}
@@ -1,25 +1,29 @@
-use std::sync::Arc;
-use tokio::time::{sleep, Duration}; // Replace std::thread::sleep and std::time::Duration
+use tokio::time::{sleep, Duration, Instant};
 
 use rand::prelude::*;
 use uuid::Uuid;
 
-use crate::constants::{*};
 use crate::dto::{*};
+use crate::handler::DynErr;
 
-use super::ai::send_request;
+use super::ai::backend::monica::{authorized, client, send_request};
 
 
 
-pub async fn process_image(data: &ImgData) -> Result<String, crate::handler::DynErr> {
+/// Uploads `data` to Monica and asks Gemini about it with `prompt`, giving Monica
+/// up to `wait` to process the upload. Images over `max_bytes` are refused.
+pub async fn process_image(data: &ImgData, prompt: &str, wait: Duration, max_bytes: usize) -> Result<String, DynErr> {
 	let filename = data.file.clone();
-	let file_size = data.file_size.parse::<u64>().unwrap();
+	let file_size = data.file_size.parse::<u64>().map_err(|_| format!("Bad file_size {} of {}", data.file_size, filename))?;
+	if file_size > max_bytes as u64 {
+		return Err(format!("Image {} is larger than {} KiB", filename, max_bytes / 1024).into());
+	}
 	let url = data.url.clone();
-	let item = upload_image(&filename, file_size, &url).await?;
-	explain_image(item).await
+	let item = upload_image(&filename, file_size, &url, wait, max_bytes).await?;
+	explain_image(item, prompt).await
 }
 
-pub async fn explain_image(img: ImageItem) -> Result<String, crate::handler::DynErr> {
+pub async fn explain_image(img: ImageItem, prompt: &str) -> Result<String, DynErr> {
 	let mut items = Vec::new();
 
 	let conv = Uuid::new_v4().to_string();
@@ -27,8 +31,8 @@ pub async fn explain_image(img: ImageItem) -> Result<String, crate::handler::Dyn
 	let start_id = Uuid::new_v4().to_string();
 
 	items.push(ConversationItem {
-		item_id: "msg:".to_owned()+&start_id,
-		conversation_id: "conv:".to_owned()+&conv,
+		item_id: "msg:".to_owned()+start_id.as_str(),
+		conversation_id: "conv:".to_owned()+conv.as_str(),
 		item_type: "reply".to_string(),
 		summary: "__RENDER_BOT_WELCOME_MSG__".to_string(),
 		parent_item_id: None,
@@ -45,14 +49,14 @@ pub async fn explain_image(img: ImageItem) -> Result<String, crate::handler::Dyn
 	let msg_id = Uuid::new_v4().to_string();
 
 	items.push(ConversationItem {
-		item_id: "msg:".to_owned()+&msg_id,
-		conversation_id: "conv:".to_owned()+&conv,
+		item_id: "msg:".to_owned()+msg_id.as_str(),
+		conversation_id: "conv:".to_owned()+conv.as_str(),
 		item_type: "question".to_string(),
-		summary: "用简洁的语言解释这张图片 ".to_string(),
-		parent_item_id: Some("msg:".to_owned()+&start_id),
+		summary: prompt.to_string(),
+		parent_item_id: Some("msg:".to_owned()+start_id.as_str()),
 		data: ItemData {
 			data_type: "file_with_text".to_string(),
-			content: "用简洁的语言解释这张图片 ".to_string(),
+			content: prompt.to_string(),
 			quote_content: None,
 			max_token: Some(0),
 			is_incognito: Some(true),
@@ -63,8 +67,8 @@ pub async fn explain_image(img: ImageItem) -> Result<String, crate::handler::Dyn
 	let cdata = ConversationData {
 		conversation_id: conv.clone(),
 		items,
-		pre_generated_reply_id: "msg:".to_owned()+&Uuid::new_v4().to_string(),
-		pre_parent_item_id: "msg:".to_owned()+&msg_id,
+		pre_generated_reply_id: "msg:".to_owned()+Uuid::new_v4().to_string().as_str(),
+		pre_parent_item_id: "msg:".to_owned()+msg_id.as_str(),
 		origin: "https://monica.im/home/chat/Gemini%202.0%20Flash/gemini_2_0",
 		origin_page_title: "Gemini 2.0 Flash - Monica Bots",
 		trigger_by: "auto",
@@ -92,31 +96,50 @@ pub async fn explain_image(img: ImageItem) -> Result<String, crate::handler::Dyn
 }
 
 
-pub async fn download_image(url: &str) -> Result<Vec<u8>, crate::handler::DynErr> {
-	let client = reqwest::Client::new();
-	let resp = client.get(url).send().await?;
+pub async fn download_image(url: &str) -> Result<Vec<u8>, DynErr> {
+	let resp = client().get(url).send().await?.error_for_status()?;
 	let bytes = resp.bytes().await?;
 	Ok(bytes.to_vec())
 }
 
-pub async fn upload_image(filename: &str, file_size: u64, url: &str) -> Result<ImageItem, crate::handler::DynErr> {
-	let bytes = download_image(url).await?;
+/// The body at `url`, fetched with `client`, refused once it passes `max_bytes`:
+/// up front when the server says how long it is, else as soon as the read goes over.
+pub async fn download_at_most(client: &reqwest::Client, url: &str, max_bytes: usize) -> Result<Vec<u8>, DynErr> {
+	let mut resp = client.get(url).send().await?.error_for_status()?;
+	let too_large = || format!("{} is larger than {} KiB", url, max_bytes / 1024).into();
+	if resp.content_length().is_some_and(|n| n > max_bytes as u64) {
+		return Err(too_large());
+	}
+	let mut bytes = Vec::new();
+	while let Some(chunk) = resp.chunk().await? {
+		if bytes.len() + chunk.len() > max_bytes {
+			return Err(too_large());
+		}
+		bytes.extend_from_slice(&chunk);
+	}
+	Ok(bytes)
+}
 
-	let jar = Arc::new(reqwest::cookie::Jar::default());
-    jar.add_cookie_str(
-        format!("session_id={}", AI_TOKEN.read().unwrap().as_str()).as_str(),
-        &reqwest::Url::parse("https://api.monica.im").unwrap()
-    );
+/// The field at `pointer` of a Monica response, or an error naming the step that failed.
+fn field<'a>(v: &'a serde_json::Value, pointer: &str, step: &str) -> Result<&'a serde_json::Value, DynErr> {
+	v.pointer(pointer).filter(|f| !f.is_null()).ok_or_else(|| format!("{}: no {} in {}", step, pointer, v).into())
+}
+
+fn str_field<'a>(v: &'a serde_json::Value, pointer: &str, step: &str) -> Result<&'a str, DynErr> {
+	field(v, pointer, step)?.as_str().ok_or_else(|| format!("{}: {} is not a string", step, pointer).into())
+}
 
-    let client = reqwest::Client::builder()
-        .cookie_provider(Arc::clone(&jar))
-        .build()?;
+/// Uploads the image at `url`, of at most `max_bytes`, to Monica's file store and waits
+/// up to `wait` for it to be indexed.
+pub async fn upload_image(filename: &str, file_size: u64, url: &str, wait: Duration, max_bytes: usize) -> Result<ImageItem, DynErr> {
+	let bytes = download_at_most(client(), url, max_bytes).await?;
+	let ext = filename.rsplit_once('.').map(|(_, e)| e).unwrap_or("jpg");
 
 	let pre_url = "https://api.monica.im/api/file_object/pre_sign_list_by_module";
 	let obj_id: String = rand::rng().sample_iter(&rand::distr::Alphanumeric).take(22).map(char::from).collect();
 
 
-	let resp = client.post(pre_url)
+	let resp = authorized(client().post(pre_url))
 		.json(&serde_json::json!({
 			"filename_list": vec![filename.to_string()],
 			"location": "files".to_string(),
@@ -124,74 +147,81 @@ pub async fn upload_image(filename: &str, file_size: u64, url: &str) -> Result<I
 			"obj_id": obj_id,
 		}))
 		.send()
-		.await?;
+		.await?
+		.error_for_status()?;
 	let pre_json = resp.json::<serde_json::Value>().await?;
 
 
-	let upload_url = pre_json["data"]["pre_sign_url_list"][0].as_str().unwrap();
-	let object_url = pre_json["data"]["object_url_list"][0].as_str().unwrap();
-	let _ = client.put(upload_url)
+	let upload_url = str_field(&pre_json, "/data/pre_sign_url_list/0", "pre-sign")?;
+	let object_url = str_field(&pre_json, "/data/object_url_list/0", "pre-sign")?;
+	client().put(upload_url)
 		.body(bytes)
 		.send()
-		.await?;
+		.await?
+		.error_for_status()?;
 
 
 	let create_url = "https://api.monica.im/api/files/batch_create_llm_file";
-	let create_resp = client.post(create_url)
+	let create_resp = authorized(client().post(create_url))
 		.json(&serde_json::json!({
 			"data":[
 				{"url":"","parse":true,
 				"file_name":filename.to_string(),
 				"file_size":file_size,
-				"file_type":filename.split('.').last().unwrap(),
+				"file_type":ext,
 				"object_url":object_url,
 				"embedding":false}
 				]}))
 		.send()
-		.await?;
+		.await?
+		.error_for_status()?;
 	let create_json = create_resp.json::<serde_json::Value>().await?;
-	let file_uid = create_json["data"]["items"][0]["file_uid"].as_str().unwrap();
+	let file_uid = str_field(&create_json, "/data/items/0/file_uid", "create")?;
 
-	let mut t = 0;
+	// Poll with a growing interval until Monica has indexed the file or `wait` runs out.
+	let deadline = Instant::now() + wait;
+	let mut interval = Duration::from_millis(500);
 	let file_tokens;
 	let file_chunks;
 	loop {
 		let check_url = "https://api.monica.im/api/files/batch_get_file";
-		let check_resp = client.post(check_url)
+		let check_resp = authorized(client().post(check_url))
 			.json(&serde_json::json!({
 				"file_uids":[
 					file_uid
 					]}))
 			.send()
-			.await?;
+			.await?
+			.error_for_status()?;
 		let check_json = check_resp.json::<serde_json::Value>().await?;
-		match check_json["data"]["items"][0]["index_state"].as_u64().unwrap() {
-			3 => {
-				file_tokens = check_json["data"]["items"][0]["file_tokens"].as_u64().unwrap();
-				file_chunks = check_json["data"]["items"][0]["file_chunks"].as_u64().unwrap();
+		match field(&check_json, "/data/items/0/index_state", "check")?.as_u64() {
+			Some(3) => {
+				file_tokens = field(&check_json, "/data/items/0/file_tokens", "check")?.as_u64().unwrap_or_default();
+				file_chunks = field(&check_json, "/data/items/0/file_chunks", "check")?.as_u64().unwrap_or_default();
 				break;
 			}
-			2 => {
-				return Err(("Upload processing error:\n".to_owned()+check_json["data"]["items"][0]["error_message"].as_str().unwrap()).into());
+			Some(2) => {
+				let reason = check_json.pointer("/data/items/0/error_message").and_then(|m| m.as_str()).unwrap_or("unknown");
+				return Err(format!("Upload processing error:\n{}", reason).into());
 			}
 			_ => {}
 		}
-		sleep(Duration::from_millis(1000)).await; // non-blocking sleep
-		t += 1;
-		if t > 5 {
-			return Err("Upload failed".to_string().into());
+		if Instant::now() + interval > deadline {
+			return Err(format!("Monica did not process {} within {:?}", filename, wait).into());
 		}
+		sleep(interval).await;
+		interval = (interval * 2).min(Duration::from_secs(4));
 	}
 
 	Ok(ImageItem {
 		use_full_text: true,
 		file_name: filename.to_string(),
-		file_type: filename.split('.').last().unwrap().to_string(),
-		file_ext: filename.split('.').last().unwrap().to_string(),
-		file_size: file_size as u64,
+		file_type: ext.to_string(),
+		file_ext: ext.to_string(),
+		file_size,
 		file_url: object_url.to_string(),
 		file_uid: file_uid.to_string(),
 		file_chunks,
 		file_tokens
 	})
-}
\ No newline at end of file
+}
@@ -0,0 +1,25 @@
+use async_trait::async_trait;
+
+use super::super::dto::Data;
+use crate::handler::DynErr;
+use crate::plugin::{MessageContext, Plugin};
+
+pub struct EchoPlugin;
+
+#[async_trait]
+impl Plugin for EchoPlugin {
+	fn name(&self) -> &'static str {
+		"echo"
+	}
+
+	fn commands(&self) -> Vec<&'static str> {
+		vec!["echo"]
+	}
+
+	async fn on_command(&self, _ctx: &MessageContext, cmd: &str, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+		if cmd != "echo" {
+			return Ok(None);
+		}
+		Ok(Some(vec![Data::string(args.join(" "))]))
+	}
+}
@@ -1,6 +1,10 @@
 use super::super::dto::Data;
+use crate::allow;
+use crate::handler::DynErr;
+use crate::plugin::{Identity, MessageContext, Plugin};
 
 use std::process::Command;
+use async_trait::async_trait;
 
 pub fn exec(msg: &str) -> Result<Vec<Data>, crate::handler::DynErr> {
 	
@@ -23,4 +27,25 @@ pub fn exec(msg: &str) -> Result<Vec<Data>, crate::handler::DynErr> {
 	
 
 	Ok(ret)
-}
\ No newline at end of file
+}
+
+pub struct ExecPlugin;
+
+#[async_trait]
+impl Plugin for ExecPlugin {
+	fn name(&self) -> &'static str {
+		"exec"
+	}
+
+	fn commands(&self) -> Vec<&'static str> {
+		vec!["exec"]
+	}
+
+	async fn on_command(&self, ctx: &MessageContext, cmd: &str, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+		if cmd != "exec" {
+			return Ok(None);
+		}
+		allow!(&ctx.sender, Identity::Owner); // Require owner for exec
+		Ok(Some(exec(&args.join(" "))?))
+	}
+}
@@ -1,4 +1,10 @@
 pub mod ping;
+pub mod echo;
+#[cfg(feature = "exec")]
 pub mod exec;
+#[cfg(feature = "ai")]
 pub mod ai;
-pub mod ai_img;
\ No newline at end of file
+#[cfg(feature = "ai")]
+pub mod ai_img;
+#[cfg(feature = "script")]
+pub mod script;
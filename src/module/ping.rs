@@ -1,5 +1,29 @@
+use async_trait::async_trait;
+
 use super::super::dto::Data;
+use crate::handler::DynErr;
+use crate::plugin::{MessageContext, Plugin};
 
 pub fn ping(name: &str) -> Vec<Data> {
 	vec![Data::string(format!("pong to {}", name))]
-}
\ No newline at end of file
+}
+
+pub struct PingPlugin;
+
+#[async_trait]
+impl Plugin for PingPlugin {
+	fn name(&self) -> &'static str {
+		"ping"
+	}
+
+	fn commands(&self) -> Vec<&'static str> {
+		vec!["ping"]
+	}
+
+	async fn on_command(&self, ctx: &MessageContext, cmd: &str, _args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+		if cmd != "ping" {
+			return Ok(None);
+		}
+		Ok(Some(ping(&ctx.nickname)))
+	}
+}
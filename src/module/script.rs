@@ -0,0 +1,336 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use log::{info, error};
+use once_cell::sync::OnceCell;
+use redis::{Client, Commands};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, NativeCallContext, Scope, AST};
+use serde_json::{json, Value};
+use tokio::time::{interval, Duration};
+
+use crate::allow;
+use crate::config::Config;
+use crate::dto::Data;
+use crate::handler::DynErr;
+use crate::plugin::{Identity, MessageContext, Plugin};
+
+struct Script {
+	modified: SystemTime,
+	ast: Arc<AST>,
+}
+
+#[derive(Default)]
+struct Scripts {
+	files: HashMap<PathBuf, Script>,
+	/// Command name -> (script file, function name).
+	commands: HashMap<String, (PathBuf, String)>,
+	/// Modification time of the last version of a file that failed to load.
+	failed: HashMap<PathBuf, SystemTime>,
+}
+
+struct Runtime {
+	engine: Engine,
+	dir: PathBuf,
+	/// Filled by `register_command` while a script's top level runs.
+	pending: Arc<Mutex<Vec<(String, String)>>>,
+	scripts: RwLock<Scripts>,
+	reload_lock: Mutex<()>,
+}
+
+fn segment(type_: &str, data: Value) -> Dynamic {
+	rhai::serde::to_dynamic(json!({ "type": type_, "data": data })).unwrap_or(Dynamic::UNIT)
+}
+
+fn script_err(e: impl ToString) -> Box<EvalAltResult> {
+	e.to_string().into()
+}
+
+/// Redis key for `key` in the namespace of the calling script.
+fn kv_key(ctx: &NativeCallContext, key: &str) -> String {
+	format!("script:{}:{}", ctx.call_source().unwrap_or("_"), key)
+}
+
+thread_local! {
+	/// The message whose command is running a script on this thread. `ai` answers as its
+	/// sender, never as whoever the script's own `ctx` map claims to be.
+	static INVOKER: RefCell<Option<Arc<MessageContext>>> = const { RefCell::new(None) };
+}
+
+/// What a script may act on of `ctx`: the real sender, detached from the live connection.
+fn invoker(ctx: &MessageContext) -> MessageContext {
+	MessageContext {
+		msg_id: ctx.msg_id,
+		group_id: ctx.group_id,
+		user_id: ctx.user_id,
+		nickname: ctx.nickname.clone(),
+		sender: ctx.sender.clone(),
+		text: ctx.text.clone(),
+		images: vec![],
+		records: vec![],
+		at_self: true,
+		db: ctx.db.clone(),
+		event: Value::Null,
+		outbox: None,
+	}
+}
+
+fn build_engine(config: &Config, db: Arc<Client>, pending: Arc<Mutex<Vec<(String, String)>>>) -> Engine {
+	let mut engine = Engine::new();
+
+	// Scripts come from teammates, not from chat, but still must not hang or exhaust the bot.
+	engine.set_max_operations(config.script.max_operations);
+	engine.set_max_call_levels(32);
+	engine.set_max_expr_depths(64, 32);
+	engine.set_max_string_size(64 * 1024);
+	engine.set_max_array_size(10_000);
+	engine.set_max_map_size(10_000);
+	engine.disable_symbol("eval");
+	engine.on_print(|s| info!("[script] {}", s));
+	engine.on_debug(|s, src, _| info!("[script {}] {}", src.unwrap_or("?"), s));
+
+	engine.register_fn("register_command", move |name: &str, func: &str| {
+		pending.lock().unwrap().push((name.to_string(), func.to_string()));
+	});
+
+	engine.register_fn("text", |s: &str| segment("text", json!({ "text": s })));
+	engine.register_fn("at", |qq: i64| segment("at", json!({ "qq": qq.to_string() })));
+	engine.register_fn("reply", |id: i64| segment("reply", json!({ "id": id })));
+
+	let kv_db = db.clone();
+	engine.register_fn("kv_get", move |ctx: NativeCallContext, key: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+		let mut conn = kv_db.get_connection().map_err(script_err)?;
+		let v: Option<String> = conn.get(kv_key(&ctx, key)).map_err(script_err)?;
+		Ok(v.map(Dynamic::from).unwrap_or(Dynamic::UNIT))
+	});
+	let kv_db = db.clone();
+	engine.register_fn("kv_set", move |ctx: NativeCallContext, key: &str, value: &str| -> Result<(), Box<EvalAltResult>> {
+		let mut conn = kv_db.get_connection().map_err(script_err)?;
+		conn.set(kv_key(&ctx, key), value).map_err(script_err)
+	});
+	let kv_db = db;
+	engine.register_fn("kv_del", move |ctx: NativeCallContext, key: &str| -> Result<(), Box<EvalAltResult>> {
+		let mut conn = kv_db.get_connection().map_err(script_err)?;
+		conn.del(kv_key(&ctx, key)).map_err(script_err)
+	});
+
+	#[cfg(feature = "ai")]
+	{
+		let handle = tokio::runtime::Handle::current();
+		// The map is the script's `ctx`, taken for compatibility only: anything in it can be forged.
+		engine.register_fn("ai", move |_ctx: rhai::Map, prompt: &str| -> Result<String, Box<EvalAltResult>> {
+			let ctx = INVOKER.with(|i| i.borrow().clone()).ok_or_else(|| script_err("ai is only available while handling a command"))?;
+			let ret = handle.block_on(crate::module::ai::main_conversation(&ctx, prompt)).map_err(script_err)?;
+			Ok(ret.iter().filter_map(|d| d.data["text"].as_str()).collect())
+		});
+	}
+
+	engine
+}
+
+impl Runtime {
+	/// Recompiles changed scripts and drops deleted ones. Blocking: scripts may touch Redis at load.
+	fn reload(&self) -> Result<usize, DynErr> {
+		let _guard = self.reload_lock.lock().unwrap();
+
+		let mut found = HashMap::new();
+		if self.dir.is_dir() {
+			for entry in std::fs::read_dir(&self.dir)? {
+				let path = entry?.path();
+				if path.extension().and_then(|e| e.to_str()) == Some("rhai") {
+					found.insert(path.clone(), std::fs::metadata(&path)?.modified()?);
+				}
+			}
+		}
+
+		let mut changed = 0;
+		let stale: Vec<PathBuf> = self.scripts.read().unwrap().files.keys().filter(|p| !found.contains_key(*p)).cloned().collect();
+		for path in stale {
+			let mut scripts = self.scripts.write().unwrap();
+			scripts.files.remove(&path);
+			scripts.commands.retain(|_, (p, _)| *p != path);
+			scripts.failed.remove(&path);
+			info!("Script unloaded: {}", path.display());
+			changed += 1;
+		}
+
+		for (path, modified) in found {
+			{
+				let scripts = self.scripts.read().unwrap();
+				if scripts.files.get(&path).map(|s| s.modified) == Some(modified) || scripts.failed.get(&path) == Some(&modified) {
+					continue;
+				}
+			}
+			match self.load(&path) {
+				Ok((ast, commands)) => {
+					let mut scripts = self.scripts.write().unwrap();
+					scripts.commands.retain(|_, (p, _)| *p != path);
+					for (name, func) in commands {
+						scripts.commands.insert(name, (path.clone(), func));
+					}
+					scripts.files.insert(path.clone(), Script { modified, ast: Arc::new(ast) });
+					scripts.failed.remove(&path);
+					info!("Script loaded: {}", path.display());
+					changed += 1;
+				}
+				Err(e) => {
+					// Keep the previous version running and don't retry until the file changes again.
+					error!("Script {} failed to load: {}", path.display(), e);
+					self.scripts.write().unwrap().failed.insert(path, modified);
+				}
+			}
+		}
+		Ok(changed)
+	}
+
+	fn load(&self, path: &Path) -> Result<(AST, Vec<(String, String)>), DynErr> {
+		let mut ast = self.engine.compile_file(path.to_path_buf()).map_err(|e| e.to_string())?;
+		ast.set_source(path.file_stem().and_then(|s| s.to_str()).unwrap_or("_"));
+
+		self.pending.lock().unwrap().clear();
+		self.engine.run_ast(&ast).map_err(|e| e.to_string())?;
+		let commands = std::mem::take(&mut *self.pending.lock().unwrap());
+		for (_, func) in &commands {
+			if !ast.iter_functions().any(|f| f.name == func) {
+				return Err(format!("register_command refers to unknown function `{}`", func).into());
+			}
+		}
+		Ok((ast, commands))
+	}
+
+	/// Runs `func` on behalf of `invoker`, the message that triggered it.
+	fn call(&self, ast: &AST, func: &str, invoker: MessageContext, ctx: Dynamic, args: Array) -> Result<Vec<Data>, DynErr> {
+		INVOKER.with(|i| *i.borrow_mut() = Some(Arc::new(invoker)));
+		let mut scope = Scope::new();
+		let ret = self.engine.call_fn::<Dynamic>(&mut scope, ast, func, (ctx, args));
+		INVOKER.with(|i| *i.borrow_mut() = None);
+		to_data(ret.map_err(|e| e.to_string())?)
+	}
+}
+
+/// Script return values: `()` for no reply, a string, one segment, or an array of either.
+fn to_data(ret: Dynamic) -> Result<Vec<Data>, DynErr> {
+	if ret.is_unit() {
+		return Ok(vec![]);
+	}
+	if ret.is_array() {
+		let mut out = vec![];
+		for item in ret.cast::<Array>() {
+			out.extend(to_data(item)?);
+		}
+		return Ok(out);
+	}
+	if ret.is_string() {
+		return Ok(vec![Data::string(ret.cast::<String>())]);
+	}
+	let v: Value = rhai::serde::from_dynamic(&ret).map_err(|e| e.to_string())?;
+	match (v["type"].as_str(), v.get("data")) {
+		(Some(type_), Some(data)) => Ok(vec![Data { type_: type_.to_string(), data: data.clone() }]),
+		_ => Ok(vec![Data::string(ret.to_string())]),
+	}
+}
+
+/// Runs Rhai scripts from the `plugins/` directory as bot commands.
+///
+/// Scripts call `register_command("name", "fn_name")` at top level; `fn_name(ctx, args)`
+/// then handles `~name args...`. Changed files are picked up without a restart.
+#[derive(Default)]
+pub struct ScriptPlugin {
+	runtime: OnceCell<Arc<Runtime>>,
+}
+
+impl ScriptPlugin {
+	pub fn new() -> ScriptPlugin {
+		ScriptPlugin::default()
+	}
+
+	async fn reload(runtime: Arc<Runtime>) -> Result<usize, DynErr> {
+		tokio::task::spawn_blocking(move || runtime.reload()).await?
+	}
+}
+
+#[async_trait]
+impl Plugin for ScriptPlugin {
+	fn name(&self) -> &'static str {
+		"script"
+	}
+
+	fn commands(&self) -> Vec<&'static str> {
+		vec!["script"]
+	}
+
+	async fn on_load(&self, config: &Config, db: Arc<Client>) -> Result<(), DynErr> {
+		let pending = Arc::new(Mutex::new(vec![]));
+		let runtime = Arc::new(Runtime {
+			engine: build_engine(config, db, pending.clone()),
+			dir: PathBuf::from(&config.script.dir),
+			pending,
+			scripts: RwLock::new(Scripts::default()),
+			reload_lock: Mutex::new(()),
+		});
+		if self.runtime.set(runtime.clone()).is_err() {
+			return Err("Script plugin loaded twice".into());
+		}
+
+		ScriptPlugin::reload(runtime.clone()).await?;
+
+		let every = Duration::from_secs(config.script.reload_interval.max(1));
+		tokio::spawn(async move {
+			let mut ticker = interval(every);
+			loop {
+				ticker.tick().await;
+				if let Err(e) = ScriptPlugin::reload(runtime.clone()).await {
+					error!("Script reload failed: {:?}", e);
+				}
+			}
+		});
+		Ok(())
+	}
+
+	async fn on_command(&self, ctx: &MessageContext, cmd: &str, args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+		let Some(runtime) = self.runtime.get() else {
+			return Ok(None);
+		};
+
+		if cmd == "script" {
+			let ret = match args.first() {
+				Some(&"reload") => {
+					allow!(&ctx.sender, Identity::Owner);
+					let n = ScriptPlugin::reload(runtime.clone()).await?;
+					format!("Reloaded {} script(s)", n)
+				}
+				_ => {
+					let scripts = runtime.scripts.read().unwrap();
+					let mut names: Vec<&String> = scripts.commands.keys().collect();
+					names.sort();
+					format!("{} script(s), commands: {}", scripts.files.len(), names.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", "))
+				}
+			};
+			return Ok(Some(vec![Data::string(ret)]));
+		}
+
+		let (ast, func) = {
+			let scripts = runtime.scripts.read().unwrap();
+			match scripts.commands.get(cmd) {
+				Some((path, func)) => (scripts.files[path].ast.clone(), func.clone()),
+				None => return Ok(None),
+			}
+		};
+
+		let mut map = rhai::Map::new();
+		map.insert("msg_id".into(), Dynamic::from(ctx.msg_id as i64));
+		map.insert("group_id".into(), Dynamic::from(ctx.group_id.unwrap_or_default() as i64));
+		map.insert("user_id".into(), Dynamic::from(ctx.user_id as i64));
+		map.insert("nickname".into(), Dynamic::from(ctx.nickname.clone()));
+		map.insert("text".into(), Dynamic::from(ctx.text.clone()));
+		let args: Array = args.iter().map(|a| Dynamic::from(a.to_string())).collect();
+
+		let runtime = runtime.clone();
+		let invoker = invoker(ctx);
+		let ret = tokio::task::spawn_blocking(move || runtime.call(&ast, &func, invoker, Dynamic::from_map(map), args)).await??;
+		Ok(Some(ret))
+	}
+}
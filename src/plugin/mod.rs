@@ -0,0 +1,229 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use redis::Client;
+use serde_json::{Map, Value};
+use log::{info, error};
+
+use crate::config::Config;
+use crate::constants::OWNER_ID;
+use crate::dto::{Data, ImgData, RecordData, RetMessage};
+use crate::handler::{DynErr, Sender};
+use crate::middleware;
+
+#[derive(PartialEq)]
+pub enum Identity {
+    Owner,
+    /// Owner or admin of the group the message came from.
+    Admin,
+    User,
+}
+
+pub fn get_identity(sender: &Map<String, Value>) -> Identity {
+    let user_id = sender["user_id"].as_u64().unwrap();
+    if user_id == *OWNER_ID.read().unwrap() {
+        Identity::Owner
+    } else if matches!(sender.get("role").and_then(|r| r.as_str()), Some("owner") | Some("admin")) {
+        Identity::Admin
+    } else {
+        Identity::User
+    }
+}
+
+/// Returns a "Permission denied" reply from the enclosing `on_command` unless
+/// the sender has the required identity.
+#[macro_export]
+macro_rules! allow {
+    ($sender:expr, $required:expr) => {
+        let identity = $crate::plugin::get_identity($sender);
+        match $required {
+            $crate::plugin::Identity::Owner if identity != $crate::plugin::Identity::Owner => {
+                return Ok(Some(vec![$crate::dto::Data::string("Permission denied: Owner required".to_string())]));
+            }
+            $crate::plugin::Identity::Admin if identity == $crate::plugin::Identity::User => {
+                return Ok(Some(vec![$crate::dto::Data::string("Permission denied: Admin required".to_string())]));
+            }
+            _ => {}
+        }
+    };
+}
+
+/// One incoming chat message, already split into text and images.
+pub struct MessageContext {
+    pub msg_id: u64,
+    /// `None` for private messages.
+    pub group_id: Option<u64>,
+    pub user_id: u64,
+    pub nickname: String,
+    pub sender: Map<String, Value>,
+    pub text: String,
+    pub images: Vec<ImgData>,
+    /// Voice messages, not yet transcribed.
+    pub records: Vec<RecordData>,
+    /// Whether the bot was @-mentioned. Always `true` in private chats.
+    pub at_self: bool,
+    pub db: Arc<Client>,
+    /// The raw OneBot event.
+    pub event: Value,
+    /// Connection for messages sent before the handler returns. `None` outside a live event.
+    pub outbox: Option<Sender>,
+}
+
+impl MessageContext {
+    /// Sends `message` to the chat this message came from right away, through the post middlewares.
+    pub async fn send(&self, message: Vec<Data>) -> Result<(), DynErr> {
+        let Some(outbox) = &self.outbox else {
+            return Err("No connection to send on".into());
+        };
+        let mut resp = crate::handler::reply_to(self.group_id, self.user_id, message);
+        middleware::post(&self.event, &mut resp, self.db.clone()).await?;
+        crate::handler::send(resp, outbox.clone()).await
+    }
+
+    /// Like `send`, but waits for the server and returns the sent message's ID.
+    pub async fn send_for_id(&self, message: Vec<Data>) -> Result<u64, DynErr> {
+        let mut resp = crate::handler::reply_to(self.group_id, self.user_id, message);
+        middleware::post(&self.event, &mut resp, self.db.clone()).await?;
+        let data = self.call_api(&resp.action, resp.params).await?;
+        data["message_id"].as_u64().ok_or_else(|| format!("No message_id in {}", data).into())
+    }
+
+    /// Calls OneBot API `action` and returns the response's `data`.
+    pub async fn call_api(&self, action: &str, params: Value) -> Result<Value, DynErr> {
+        let Some(outbox) = &self.outbox else {
+            return Err("No connection to call the API on".into());
+        };
+        crate::handler::call_api(action, params, outbox.clone()).await
+    }
+}
+
+/// A unit of bot functionality.
+///
+/// Every hook has a no-op default, so a plugin only implements what it needs.
+/// Hooks returning `Ok(None)` let the event fall through to the next plugin.
+#[async_trait]
+pub trait Plugin: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Commands this plugin answers to, without the leading `~`. Used for listing only.
+    fn commands(&self) -> Vec<&'static str> {
+        vec![]
+    }
+
+    async fn on_load(&self, _config: &Config, _db: Arc<Client>) -> Result<(), DynErr> {
+        Ok(())
+    }
+
+    /// Called for every message that is not a command.
+    async fn on_message(&self, _ctx: &MessageContext) -> Result<Option<Vec<Data>>, DynErr> {
+        Ok(None)
+    }
+
+    async fn on_notice(&self, _event: &Value, _db: Arc<Client>) -> Result<Option<RetMessage>, DynErr> {
+        Ok(None)
+    }
+
+    async fn on_command(&self, _ctx: &MessageContext, _cmd: &str, _args: &[&str]) -> Result<Option<Vec<Data>>, DynErr> {
+        Ok(None)
+    }
+
+    async fn on_shutdown(&self) {}
+}
+
+#[derive(Default)]
+pub struct PluginRegistry {
+    plugins: Vec<Box<dyn Plugin>>,
+}
+
+impl PluginRegistry {
+    pub fn new() -> PluginRegistry {
+        PluginRegistry::default()
+    }
+
+    /// A registry holding every built-in module enabled at compile time.
+    pub fn with_builtins() -> PluginRegistry {
+        let mut registry = PluginRegistry::new();
+        registry.register(Box::new(crate::module::echo::EchoPlugin));
+        registry.register(Box::new(crate::module::ping::PingPlugin));
+        #[cfg(feature = "exec")]
+        registry.register(Box::new(crate::module::exec::ExecPlugin));
+        #[cfg(feature = "ai")]
+        registry.register(Box::new(crate::module::ai::AiPlugin));
+        #[cfg(feature = "ai")]
+        registry.register(Box::new(crate::module::ai::kb::KbPlugin));
+        #[cfg(feature = "ai")]
+        registry.register(Box::new(crate::module::ai::draw::DrawPlugin));
+        #[cfg(feature = "ai")]
+        registry.register(Box::new(crate::module::ai::speech::SayPlugin));
+        #[cfg(feature = "script")]
+        registry.register(Box::new(crate::module::script::ScriptPlugin::new()));
+        registry
+    }
+
+    /// Plugins are consulted in registration order.
+    pub fn register(&mut self, plugin: Box<dyn Plugin>) -> &mut PluginRegistry {
+        self.plugins.push(plugin);
+        self
+    }
+
+    pub fn plugins(&self) -> &[Box<dyn Plugin>] {
+        &self.plugins
+    }
+
+    pub async fn load(&self, config: &Config, db: Arc<Client>) {
+        for p in &self.plugins {
+            match p.on_load(config, db.clone()).await {
+                Ok(()) => info!("Plugin loaded: {}", p.name()),
+                Err(e) => error!("Plugin {} failed to load: {:?}", p.name(), e),
+            }
+        }
+    }
+
+    pub async fn command(&self, ctx: &MessageContext, cmd: &str, args: &[&str]) -> Result<Vec<Data>, DynErr> {
+        for p in &self.plugins {
+            if let Some(ret) = p.on_command(ctx, cmd, args).await? {
+                return Ok(ret);
+            }
+        }
+        Ok(vec![Data::string("Unknown command".to_string())])
+    }
+
+    pub async fn message(&self, ctx: &MessageContext) -> Result<Option<Vec<Data>>, DynErr> {
+        for p in &self.plugins {
+            if let Some(ret) = p.on_message(ctx).await? {
+                return Ok(Some(ret));
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn notice(&self, event: &Value, db: Arc<Client>) -> Result<Option<RetMessage>, DynErr> {
+        for p in &self.plugins {
+            if let Some(ret) = p.on_notice(event, db.clone()).await? {
+                return Ok(Some(ret));
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn shutdown(&self) {
+        for p in &self.plugins {
+            p.on_shutdown().await;
+        }
+    }
+}
+
+static REGISTRY: OnceCell<PluginRegistry> = OnceCell::new();
+
+/// Makes `plugins` the process-wide registry. Panics if called twice.
+pub fn install(plugins: PluginRegistry) -> &'static PluginRegistry {
+    if REGISTRY.set(plugins).is_err() {
+        panic!("Plugin registry already installed");
+    }
+    registry()
+}
+
+pub fn registry() -> &'static PluginRegistry {
+    REGISTRY.get().expect("Plugin registry not installed")
+}